@@ -1,7 +1,13 @@
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::sync::Arc;
 // use std::sync::mpsc::Sender;
-use cart::Cart;
-use ringbuf::Producer;
+use ringbuf::Producer;
+use blip::{BlipBuf, Quality};
+use mixer::{Mixer, PULSE1, PULSE2, TRIANGLE, NOISE, DMC, EXPANSION};
+use audio::{AudioStats, FilterProfile, OutputFilter, rate_adjustment};
+use recorder::Recorder;
+use vgm::VgmLogger;
+use expansion::ExpansionAudio;
 
 const LEN_TABLE: [u8;32] = [
     10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
@@ -21,26 +27,68 @@ pub struct APU {
     interrupt: bool,
     four_step: bool,
     even_clock: bool,
+    // $4017 writes take effect 3 or 4 cycles later
+    frame_reset_delay: usize,
+    frame_reset_value: u8,
 
     pulse_mix_table: [f32; 31],
     tri_noise_dmc_mix_table: [f32; 203],
 
-    pub output: Producer<f32>,
+    pub mixer: Mixer,
+    // sound chip on the cartridge, added after the nonlinear mix
+    expansion: Option<Box<dyn ExpansionAudio>>,
+
+    // every change of the mixed level goes in as a band-limited step,
+    // one buffer and filter chain per output channel
+    blips: Vec<BlipBuf>,
+    blip_time: usize,
+    last_levels: [f32; 2],
+    channel_samples: Vec<Vec<f32>>,
+    filters: Vec<OutputFilter>,
+    // interleaved when in stereo
+    samples: Vec<f32>,
+    sample_rate: f64,
+    // current dynamic rate control adjustment
+    pub rate_ratio: f64,
+    stats: Arc<AudioStats>,
+    quality: Quality,
+    filter_profile: FilterProfile,
+    recorder: Option<Recorder>,
+
+    // CPU cycles since power on, the VGM log's timestamps
+    cycles: u64,
+    // last value written to each register, replayed when logging starts
+    registers: [u8; 0x18],
+    vgm: Option<VgmLogger>,
+
+    pub output: Producer<f32>,
 //    pub output: Arc<Mutex<Vec<f32>>>,
 //    output2: Sender<f32>,
 }
 
+// in CPU cycles since the sequence was last reset
 const STEP1: usize = 7457;
 const STEP2: usize = 14913;
 const STEP3: usize = 22371;
 const STEP4: usize = 29829;
 const IRQSTEP: usize = 29828;
 const STEP5: usize = 37281;
+// the 4-step sequence raises the IRQ on its last 3 cycles, the last one
+// also being cycle 0 of the next sequence
+const FOUR_STEP_LEN: usize = 29830;
+const FIVE_STEP_LEN: usize = 37282;
+
+pub const CPU_CLOCK: f64 = 1_789_773.0;
+// how many cycles go into the resampler at a time
+const BLIP_FRAME: usize = 1024;
 
 impl APU {
     // pub fn new(tx: Sender<f32>) -> APU {
     // pub fn new() -> APU {
-    pub fn new(prod: Producer<f32>) -> APU {
+    pub fn new(prod: Producer<f32>, sample_rate: f64, quality: Quality,
+               filter: FilterProfile, mixer: Mixer, stats: Arc<AudioStats>) -> APU {
+        let channels = mixer.output_channels();
+
         let triangle = Triangle::new();
         let pulse1 = Pulse::new(true);
         let pulse2 = Pulse::new(false);
@@ -70,22 +118,43 @@ impl APU {
             interrupt: false,
             four_step: true,
             even_clock: false,
+            frame_reset_delay: 0,
+            frame_reset_value: 0,
 
             pulse_mix_table: pmt,
             tri_noise_dmc_mix_table: tndmt,
 
+            mixer,
+            expansion: None,
+
+            blips: (0..channels).map(|_| BlipBuf::new(CPU_CLOCK, sample_rate, quality)).collect(),
+            blip_time: 0,
+            last_levels: [0.0; 2],
+            channel_samples: vec![Vec::with_capacity(BLIP_FRAME); channels],
+            filters: (0..channels).map(|_| OutputFilter::new(filter, sample_rate)).collect(),
+            samples: Vec::with_capacity(BLIP_FRAME * channels),
+            sample_rate,
+            rate_ratio: 1.0,
+            stats,
+            quality,
+            filter_profile: filter,
+            recorder: None,
+
+            cycles: 0,
+            registers: [0; 0x18],
+            vgm: None,
+
             //output: Arc::new(Mutex::new(Vec::new())),
-            output: prod,
+            output: prod,
             // output2: tx,
         }
     }
 
-    pub fn tick(&mut self, ticks: isize, cart: &Cart) -> bool {
+    pub fn tick(&mut self, ticks: isize) -> bool {
 
         for _ in 0..ticks {
-            self.frame_clock = (self.frame_clock + 1) % 29830;
-            // 5-step frame clock goes up to 37282..
-
+            self.cycles += 1;
+            self.frame_clock += 1;
             self.frame_counter();
 
             self.even_clock = !self.even_clock;
@@ -117,29 +186,238 @@ impl APU {
 
             self.dmc.period_counter -= 1;
             if self.dmc.period_counter == 0 {
-                self.dmc.period_counter = self.dmc.period + 1;
-                self.dmc.play_dmc(cart);
+                // the rates are already in CPU cycles
+                self.dmc.period_counter = self.dmc.period;
+                self.dmc.play_dmc();
+            }
+            if self.dmc.start_delay > 0 {
+                self.dmc.start_delay -= 1;
+                if self.dmc.start_delay == 0 {
+                    self.dmc.request_sample();
+                }
+            }
+
+            if let Some(ref mut chip) = self.expansion {
+                chip.clock();
             }
 
-            if self.frame_clock % 41 == 0 {
-                let output = self.pulse_mix_table[
-                                self.pulse1.output as usize +
-                                self.pulse2.output as usize
-
-                            ] + self.tri_noise_dmc_mix_table[
-                                3 * self.triangle.output as usize +
-                                2 * self.noise.output as usize +
-                                self.dmc.output // DMC is not multiplied
-                            ];
-//                self.output.lock().unwrap().insert(0, output);
-                self.output.push(output);
-                // self.output2.send(output);
+            let outputs = self.channel_outputs();
+            let (left, right) = self.mixer.mix(&outputs);
+            let levels = [left, right];
+            for (side, blip) in self.blips.iter_mut().enumerate() {
+                if levels[side] != self.last_levels[side] {
+                    let delta = levels[side] - self.last_levels[side];
+                    blip.add_delta(self.blip_time, delta);
+                    self.last_levels[side] = levels[side];
+                }
+            }
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.add(self.blip_time, &outputs, levels);
+            }
+            self.blip_time += 1;
+            if self.blip_time == BLIP_FRAME {
+                self.end_audio_frame();
             }
 
         }
-        // TODO: return interrupts as necessary
         return self.interrupt || self.dmc.irq;
-        // false
+    }
+
+    // each channel's share of the nonlinear mix
+    fn channel_outputs(&self) -> [f32; 6] {
+        let mut outputs = [0.0; 6];
+
+        let pulse1 = self.pulse1.output as usize;
+        let pulse2 = self.pulse2.output as usize;
+        let pulse_sum = pulse1 + pulse2;
+        if pulse_sum > 0 {
+            let pulse = self.pulse_mix_table[pulse_sum] / pulse_sum as f32;
+            outputs[PULSE1] = pulse * pulse1 as f32;
+            outputs[PULSE2] = pulse * pulse2 as f32;
+        }
+
+        let triangle = 3 * self.triangle.output as usize;
+        let noise = 2 * self.noise.output as usize;
+        let dmc = self.dmc.output; // DMC is not multiplied
+        let tnd_sum = triangle + noise + dmc;
+        if tnd_sum > 0 {
+            let tnd = self.tri_noise_dmc_mix_table[tnd_sum] / tnd_sum as f32;
+            outputs[TRIANGLE] = tnd * triangle as f32;
+            outputs[NOISE] = tnd * noise as f32;
+            outputs[DMC] = tnd * dmc as f32;
+        }
+
+        if let Some(ref chip) = self.expansion {
+            outputs[EXPANSION] = chip.output();
+        }
+        outputs
+    }
+
+    fn end_audio_frame(&mut self) {
+        let channels = self.blips.len();
+        for side in 0..channels {
+            self.blips[side].end_frame(self.blip_time);
+            self.channel_samples[side].clear();
+            self.blips[side].read_samples(&mut self.channel_samples[side]);
+        }
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.end_frame(self.blip_time).err(),
+            None => None,
+        };
+        if let Some(e) = failed {
+            println!("Recording stopped: {}", e);
+            self.recorder = None;
+        }
+        self.blip_time = 0;
+
+        // every buffer runs at the same rate so they all have the same count
+        self.samples.clear();
+        for n in 0..self.channel_samples[0].len() {
+            for side in 0..channels {
+                let sample = self.filters[side].process(self.channel_samples[side][n]);
+                self.samples.push(sample);
+            }
+        }
+
+        // only whole frames go in so the channels stay interleaved
+        let room = self.output.remaining() / channels * channels;
+        if room < self.samples.len() {
+            self.stats.overrun();
+        }
+        let count = if room < self.samples.len() {
+            room
+        } else {
+            self.samples.len()
+        };
+        self.output.push_slice(&self.samples[..count]);
+
+        self.rate_ratio = rate_adjustment(self.output.len(), self.output.capacity());
+        for blip in self.blips.iter_mut() {
+            blip.set_rates(CPU_CLOCK, self.sample_rate * self.rate_ratio);
+        }
+    }
+
+    // the output rate is only known once the audio device is open
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        for filter in self.filters.iter_mut() {
+            filter.set_sample_rate(sample_rate);
+        }
+        for blip in self.blips.iter_mut() {
+            blip.set_rates(CPU_CLOCK, sample_rate * self.rate_ratio);
+        }
+    }
+
+    // the mix, or each channel before the mixer when stems is set
+    pub fn start_recording(&mut self, path: &str, stems: bool) -> io::Result<()> {
+        self.stop_recording()?;
+        let recorder = Recorder::new(path,
+                                     stems,
+                                     self.mixer.output_channels(),
+                                     CPU_CLOCK,
+                                     self.sample_rate,
+                                     self.quality,
+                                     self.filter_profile)?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    // the files written, empty if nothing was being recorded
+    pub fn stop_recording(&mut self) -> io::Result<Vec<String>> {
+        match self.recorder.take() {
+            Some(recorder) => {
+                let paths = recorder.paths.clone();
+                recorder.finish(self.blip_time)?;
+                Ok(paths)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn set_expansion(&mut self, chip: Option<Box<dyn ExpansionAudio>>) {
+        self.expansion = chip;
+    }
+
+    // $4020-$FFFF, the cart sees these too
+    pub fn write_expansion(&mut self, addr: u16, value: u8) {
+        if let Some(ref mut chip) = self.expansion {
+            chip.write(addr, value);
+        }
+    }
+
+    pub fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        match self.expansion {
+            Some(ref mut chip) => chip.read(addr),
+            None => None,
+        }
+    }
+
+    fn log_write(&mut self, addr: u16, value: u8) {
+        self.registers[(addr & 0x1F) as usize] = value;
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.write(self.cycles, addr, value);
+        }
+    }
+
+    // the sample has to be logged before the registers, see CPU::start_vgm
+    pub fn start_vgm(&mut self, path: &str) -> io::Result<()> {
+        self.stop_vgm()?;
+        self.vgm = Some(VgmLogger::new(path, CPU_CLOCK, self.cycles)?);
+        Ok(())
+    }
+
+    // puts the player's APU in the same state as this one
+    pub fn log_registers(&mut self) {
+        if let Some(ref mut vgm) = self.vgm {
+            let order = (0x10..0x14).chain(vec![0x15, 0x17]).chain(0x00..0x10);
+            for reg in order {
+                if reg != 0x09 && reg != 0x0D {
+                    vgm.write(self.cycles, 0x4000 + reg, self.registers[reg as usize]);
+                }
+            }
+        }
+    }
+
+    // the sample $4012/$4013 point to, if it should be logged
+    pub fn vgm_sample_range(&self) -> Option<(usize, usize)> {
+        self.vgm.as_ref().map(|_| (self.dmc.sample_start_addr, self.dmc.sample_len))
+    }
+
+    pub fn log_dmc_byte(&mut self, addr: u16, value: u8) {
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.dmc_byte(addr, value);
+        }
+    }
+
+    pub fn set_vgm_loop(&mut self) {
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.set_loop(self.cycles);
+        }
+    }
+
+    // the file written, if there was one
+    pub fn stop_vgm(&mut self) -> io::Result<Option<String>> {
+        match self.vgm.take() {
+            Some(vgm) => {
+                let path = vgm.path.clone();
+                vgm.finish()?;
+                Ok(Some(path))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn vgm_logging(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    // sample frames waiting for the audio device
+    pub fn buffered(&self) -> usize {
+        self.output.len() / self.blips.len()
     }
 
     fn quarter_frame (&mut self) {
@@ -165,9 +443,21 @@ impl APU {
     }
 
     fn frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_clock = 0;
+                self.four_step = self.frame_reset_value & (1 << 7) == 0;
+                // 5-step mode clocks everything straight away
+                if !self.four_step {
+                    self.half_frame();
+                }
+                return;
+            }
+        }
+
         if self.four_step {
             match self.frame_clock {
-                0 => self.irq_check(),
                 // 3728.5 - QTR
                 STEP1 => self.quarter_frame(),
                 // 7456.5 - HALF
@@ -181,15 +471,29 @@ impl APU {
                     self.half_frame();
                     self.irq_check();
                 }
+                FOUR_STEP_LEN => {
+                    self.irq_check();
+                    self.frame_clock = 0;
+                }
                 _ => {}
             }
         } else {
-            // println!("5 step")
+            match self.frame_clock {
+                STEP1 => self.quarter_frame(),
+                STEP2 => self.half_frame(),
+                STEP3 => self.quarter_frame(),
+                // nothing happens at 29829 in this mode
+                // 18640.5 - HALF
+                STEP5 => self.half_frame(),
+                FIVE_STEP_LEN => self.frame_clock = 0,
+                _ => {}
+            }
         }
     }
 
     pub fn write(&mut self, addr: u16, value: u8) {
         let reg = addr & 0x1F;
+        self.log_write(addr, value);
         match reg {
             0x00 => self.pulse1.write_4000_4004(value),
             0x01 => self.pulse1.write_4001_4005(value),
@@ -216,8 +520,17 @@ impl APU {
 
 //            0x15 => self.write_status_reg(value),
             0x17 => {
-                self.four_step = (value & (1 << 7)) != 0;
                 self.interrupt_disable = (value & (1 << 6)) != 0;
+                if self.interrupt_disable {
+                    self.interrupt = false;
+                }
+                // 3 cycles if written during an APU cycle, 4 if between them
+                self.frame_reset_value = value;
+                self.frame_reset_delay = if self.even_clock {
+                    3
+                } else {
+                    4
+                };
             }
             _ => {}
         }
@@ -257,16 +570,25 @@ impl APU {
         value
     }
 
-    pub fn write_status_reg(&mut self, value: u8, cart: &Cart) {
+    pub fn write_status_reg(&mut self, value: u8) {
+        self.log_write(0x4015, value);
+        // any write acknowledges the DMC IRQ, reads leave it alone
+        self.dmc.irq = false;
         self.dmc.enabled = (value & (1 << 4)) != 0;        //D
         if !self.dmc.enabled {
-            self.dmc.bytes_remaining = 0
+            self.dmc.bytes_remaining = 0;
+            self.dmc.dma_request = false;
+            self.dmc.start_delay = 0;
         } else {
             if self.dmc.bytes_remaining == 0 {
                 self.dmc.sample_addr = self.dmc.sample_start_addr;
                 self.dmc.bytes_remaining = self.dmc.sample_len;
+                // With a byte still in the buffer the restart waits for the
+                // output unit to take it. Otherwise the reader starts 2 or 3
+                // cycles later, depending on which half of the get/put pair
+                // the write landed on.
                 if !self.dmc.sample_has_data {
-                    self.dmc.load_dmc_sample(cart);
+                    self.dmc.start_delay = if self.even_clock { 3 } else { 2 };
                 }
             }
         }
@@ -286,6 +608,28 @@ impl APU {
         if !self.pulse1.enabled {self.pulse1.length = 0};
         self.pulse1.output_pulse();
     }
+
+    // the DMC can't read memory itself, it asks the CPU to halt and
+    // fetch the next sample byte for it
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        if self.dmc.dma_request {
+            Some(self.dmc.sample_addr as u16)
+        } else {
+            None
+        }
+    }
+
+    pub fn dmc_dma_complete(&mut self, value: u8) {
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.dmc_byte(self.dmc.sample_addr as u16, value);
+        }
+        self.dmc.fill_sample(value);
+    }
+
+    // DMA has to line up with the APU's get/put cycles
+    pub fn even_cycle(&self) -> bool {
+        self.even_clock
+    }
 }
 
 const DMC_PERIOD: [usize; 16] = [
@@ -311,6 +655,9 @@ struct Dmc {
     sample_len: usize,
     sample_buffer: u8,
     sample_has_data: bool,
+    dma_request: bool,
+    // cycles until the first DMA after enabling through $4015
+    start_delay: usize,
     shift_reg: u8,
     dpcm_active: bool,
 
@@ -333,12 +680,15 @@ impl Dmc {
             period: DMC_PERIOD[0],
             period_counter: 1,
 
-            sample_start_addr: 0x8000,
-            sample_addr: 0x8000,
+            // $4012 = 0
+            sample_start_addr: 0xC000,
+            sample_addr: 0xC000,
 
             sample_len: 1,
             sample_buffer: 0,
             sample_has_data: false,
+            dma_request: false,
+            start_delay: 0,
             shift_reg: 0xFF,
             dpcm_active: false,
 
@@ -370,10 +720,20 @@ impl Dmc {
         // println!("DMC len {:#X}", self.sample_len);
     }
 
-    // needs cart
-    fn load_dmc_sample (&mut self, cart: &Cart) {
-        // println!("Try to load dmc sample");
-        self.sample_buffer = cart.read_cart_u8(self.sample_addr as u16);
+    // Memory reader: whenever the sample buffer is empty and there are bytes
+    // left it asks for a DMA, the CPU does the read over the whole bus so
+    // mappers switching the sample area in and out work
+    fn request_sample (&mut self) {
+        if !self.sample_has_data && self.bytes_remaining > 0 {
+            self.dma_request = true;
+        }
+    }
+
+    // $FFFF wraps to $8000, the end of a sample either restarts it or
+    // raises the IRQ
+    fn fill_sample (&mut self, value: u8) {
+        self.dma_request = false;
+        self.sample_buffer = value;
         // println!("DMC sample {:#X}", self.sample_buffer);
         self.sample_has_data = true;
 
@@ -391,7 +751,11 @@ impl Dmc {
         }
     }
 
-    pub fn play_dmc (&mut self, cart: &Cart) {
+    // Output unit: every timer clock shifts one bit out into the level. After
+    // 8 of them the next byte comes from the sample buffer, and if that's
+    // empty the silence flag leaves the level alone for the whole next 8 bit
+    // output cycle
+    pub fn play_dmc (&mut self) {
         if self.dpcm_active {
             if self.shift_reg & 1 == 1 {
                 if self.output < 126 {
@@ -415,9 +779,7 @@ impl Dmc {
                 self.sample_has_data = false;
             }
 
-            if self.bytes_remaining > 0 {
-                self.load_dmc_sample(cart)
-            }
+            self.request_sample();
         }
     }
 }
@@ -470,8 +832,8 @@ impl Pulse {
             wave_pos: 0,
 
             constant_vol: false,
-
-            volume: 15,
+
+            volume: 15,
             envelope_volume: 15,
             envelope_divider: 0,
             envelope_start: true,
@@ -514,7 +876,7 @@ impl Pulse {
 
         self.target_sweep();
         self.output_pulse();
-    }
+    }
 
     pub fn write_4002_4006 (&mut self, value: u8) {
         self.period = (self.period & 0xFF00) | value as usize;
@@ -523,12 +885,12 @@ impl Pulse {
         self.output_pulse();
     }
 
-    pub fn write_4003_4007 (&mut self, value: u8) {
+    pub fn write_4003_4007 (&mut self, value: u8) {
         if self.enabled {
             self.length = LEN_TABLE[value as usize >> 3];
-        } else {
-            self.length = 0;
-        }
+        } else {
+            self.length = 0;
+        }
         self.period = (self.period & 0xFF) | ((value as usize & 7) << 8);
 
         self.wave_pos = 0;
@@ -579,12 +941,12 @@ impl Pulse {
 
     fn output_pulse (&mut self) {
         self.output = if self.length == 0 ||
-                         self.period < 8 ||
+                         self.period < 8 ||
                          self.sweep_target > 0x7FF ||
                          PULSE_DUTY[self.duty][self.wave_pos] == 0
-        {
-            0
-        } else {
+        {
+            0
+        } else {
             if self.constant_vol {
                 // if self.pulse1 {println!("C {}", self.envelope_volume)};
                 self.volume
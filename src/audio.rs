@@ -0,0 +1,135 @@
+// Keeps the emulator and the audio device in step. The main loop waits on
+// the ring buffer instead of a timer, and the resampling ratio is nudged by
+// up to half a percent depending on how full the buffer is, so it settles
+// at the target latency without ever having to skip or repeat samples.
+// The console's analogue output filters live here too.
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// the most the output rate is ever changed by
+const MAX_RATE_DELTA: f64 = 0.005;
+
+pub struct AudioStats {
+    underruns: AtomicUsize,
+    overruns: AtomicUsize,
+}
+
+impl AudioStats {
+    pub fn new() -> AudioStats {
+        AudioStats {
+            underruns: AtomicUsize::new(0),
+            overruns: AtomicUsize::new(0),
+        }
+    }
+
+    // the callback ran out of samples
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the ring buffer was full and samples were dropped
+    pub fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
+    }
+}
+
+// The ring buffer holds twice the latency, so half full is on target.
+// Fuller than that means slightly fewer samples per emulated second.
+pub fn rate_adjustment(buffered: usize, capacity: usize) -> f64 {
+    let fill = buffered as f64 / capacity as f64;
+    1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill)
+}
+
+// samples in the ring buffer for a latency in milliseconds
+pub fn latency_samples(latency_ms: usize, sample_rate: i32) -> usize {
+    let samples = latency_ms * sample_rate as usize / 1000;
+    if samples < 256 {
+        256
+    } else {
+        samples
+    }
+}
+
+// The analogue parts after the DACs. The NES has two high-pass filters and
+// a low-pass, the Famicom a single lower high-pass and the same low-pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterProfile {
+    Nes,
+    Famicom,
+    Off,
+}
+
+impl FilterProfile {
+    pub fn parse(name: &str) -> Option<FilterProfile> {
+        match name {
+            "nes" => Some(FilterProfile::Nes),
+            "famicom" => Some(FilterProfile::Famicom),
+            "off" => Some(FilterProfile::Off),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    HighPass(f32),
+    LowPass(f32),
+}
+
+// first order RC filters, one after another
+pub struct OutputFilter {
+    profile: FilterProfile,
+    stages: Vec<(Stage, f32, f32)>,
+}
+
+impl OutputFilter {
+    pub fn new(profile: FilterProfile, sample_rate: f64) -> OutputFilter {
+        let mut filter = OutputFilter {
+            profile,
+            stages: Vec::new(),
+        };
+        filter.set_sample_rate(sample_rate);
+        filter
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        let dt = 1.0 / sample_rate;
+        let high_pass = |cutoff: f64| {
+            let rc = 1.0 / (2.0 * PI * cutoff);
+            Stage::HighPass((rc / (rc + dt)) as f32)
+        };
+        let low_pass = |cutoff: f64| {
+            let rc = 1.0 / (2.0 * PI * cutoff);
+            Stage::LowPass((dt / (rc + dt)) as f32)
+        };
+        let stages = match self.profile {
+            FilterProfile::Nes => vec![high_pass(90.0), high_pass(440.0), low_pass(14000.0)],
+            FilterProfile::Famicom => vec![high_pass(37.0), low_pass(14000.0)],
+            FilterProfile::Off => vec![],
+        };
+        // (stage, previous input, previous output)
+        self.stages = stages.into_iter().map(|stage| (stage, 0.0, 0.0)).collect();
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let mut sample = sample;
+        for &mut (stage, ref mut last_in, ref mut last_out) in self.stages.iter_mut() {
+            let out = match stage {
+                Stage::HighPass(a) => a * (*last_out + sample - *last_in),
+                Stage::LowPass(b) => *last_out + b * (sample - *last_out),
+            };
+            *last_in = sample;
+            *last_out = out;
+            sample = out;
+        }
+        sample
+    }
+}
@@ -0,0 +1,132 @@
+// Band-limited step synthesis in the style of blip_buf. Rather than
+// sampling the APU's output, every change in its level is added as a
+// windowed sinc step at its exact position between output samples, so the
+// 1.789773 MHz signal can go to any output rate without aliasing.
+
+const PHASES: usize = 64;
+// fraction of the output rate the kernel passes, a bit under Nyquist
+const CUTOFF: f64 = 0.45;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    pub fn parse(name: &str) -> Option<Quality> {
+        match name {
+            "low" => Some(Quality::Low),
+            "medium" => Some(Quality::Medium),
+            "high" => Some(Quality::High),
+            _ => None,
+        }
+    }
+
+    // kernel width in output samples, more is sharper but slower
+    fn taps(&self) -> usize {
+        match *self {
+            Quality::Low => 8,
+            Quality::Medium => 16,
+            Quality::High => 32,
+        }
+    }
+}
+
+pub struct BlipBuf {
+    taps: usize,
+    kernel: Vec<f32>,
+    // output samples per clock
+    ratio: f64,
+    // position of the start of the frame in output samples
+    pos: f64,
+    // deltas, integrated as samples are read
+    buffer: Vec<f32>,
+    integrator: f32,
+}
+
+impl BlipBuf {
+    pub fn new(clock_rate: f64, sample_rate: f64, quality: Quality) -> BlipBuf {
+        let taps = quality.taps();
+        let mut kernel = vec![0.0; PHASES * taps];
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            for k in 0..taps {
+                let x = k as f64 - (taps / 2 - 1) as f64 - frac;
+                let value = sinc(2.0 * CUTOFF * x) * blackman((x + (taps / 2) as f64) / taps as f64);
+                kernel[phase * taps + k] = value as f32;
+                sum += value;
+            }
+            // every phase adds up to exactly the step
+            for k in 0..taps {
+                kernel[phase * taps + k] /= sum as f32;
+            }
+        }
+
+        BlipBuf {
+            taps,
+            kernel,
+            ratio: sample_rate / clock_rate,
+            pos: 0.0,
+            buffer: Vec::new(),
+            integrator: 0.0,
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.ratio = sample_rate / clock_rate;
+    }
+
+    // a step of delta, time clocks after the start of the frame
+    pub fn add_delta(&mut self, time: usize, delta: f32) {
+        let pos = self.pos + time as f64 * self.ratio;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * PHASES as f64) as usize;
+        if self.buffer.len() < index + self.taps {
+            self.buffer.resize(index + self.taps, 0.0);
+        }
+
+        let kernel = &self.kernel[phase * self.taps..(phase + 1) * self.taps];
+        for (sample, k) in self.buffer[index..index + self.taps].iter_mut().zip(kernel) {
+            *sample += delta * k;
+        }
+    }
+
+    // nothing can be added before the end of the frame after this
+    pub fn end_frame(&mut self, clocks: usize) {
+        self.pos += clocks as f64 * self.ratio;
+    }
+
+    pub fn samples_avail(&self) -> usize {
+        self.pos as usize
+    }
+
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.samples_avail();
+        if self.buffer.len() < count {
+            self.buffer.resize(count, 0.0);
+        }
+        for delta in self.buffer.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.pos -= count as f64;
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * ::std::f64::consts::PI;
+        x.sin() / x
+    }
+}
+
+// 0 to 1 across the kernel
+fn blackman(t: f64) -> f64 {
+    let angle = 2.0 * ::std::f64::consts::PI * t;
+    0.42 - 0.5 * angle.cos() + 0.08 * (2.0 * angle).cos()
+}
@@ -3,6 +3,8 @@ use std::fs::File;
 use std::io::Read;
 
 use mem_map::*;
+use expansion::ExpansionAudio;
+use vrc6::Vrc6;
 const INES_OFFSET: usize = 0x10;
 
 #[derive(Debug)]
@@ -14,6 +16,8 @@ pub struct ChrRom {
     pub horizontal_mirroring: bool,
     pub vertical_mirroring: bool,
     pub four_screen_vram: bool,
+    // with neither of the above, which nametable all four show
+    pub one_screen_upper: bool,
 
     mapper: u8,
 
@@ -35,6 +39,11 @@ pub struct ChrRom {
     irq_enabled: bool,
     irq_reload_flag: bool,
     last_irq_clock: isize,
+
+    // VRC IRQ, counting up from the latch to $FF
+    irq_enable_after_ack: bool,
+    irq_cycle_mode: bool,
+    irq_prescaler: isize,
 }
 
 // TODO: separate rom_file reads to read only the relevant parts
@@ -50,6 +59,7 @@ impl ChrRom {
             horizontal_mirroring: romfile[6] & (1 << 0) == 0,
             vertical_mirroring: romfile[6] & (1 << 0) != 0,
             four_screen_vram: romfile[6] & (2 << 3) != 0,
+            one_screen_upper: false,
             //           prg_ram_present: false,
             //           trainer: false,
             mapper: (romfile[6] & 0b11110000) >> 4 | romfile[7] & 0b11110000,
@@ -72,6 +82,10 @@ impl ChrRom {
             irq_enabled: false,
             irq_reload_flag: false,
             last_irq_clock: 0,
+
+            irq_enable_after_ack: false,
+            irq_cycle_mode: false,
+            irq_prescaler: 341,
         };
 
         if chr.chr_rom_banks != 0 {
@@ -192,6 +206,54 @@ impl ChrRom {
         return self.irq;
     }
 
+    // The VRC IRQ runs off the CPU clock, either every cycle or once a
+    // scanline, which the prescaler makes by taking 3 off 341 each cycle.
+    pub fn cpu_clock(&mut self, cycles: isize) -> bool {
+        if self.mapper != 24 && self.mapper != 26 {
+            return false;
+        }
+        if self.irq_enabled {
+            for _ in 0..cycles {
+                if self.irq_cycle_mode {
+                    self.vrc_irq_count();
+                } else {
+                    self.irq_prescaler -= 3;
+                    if self.irq_prescaler <= 0 {
+                        self.irq_prescaler += 341;
+                        self.vrc_irq_count();
+                    }
+                }
+            }
+        }
+        self.irq
+    }
+
+    fn vrc_irq_count(&mut self) {
+        if self.irq_counter == 0xFF {
+            self.irq_counter = self.irq_latch;
+            self.irq = true;
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+
+    // $F001, the counter restarts from the latch when it's enabled
+    fn vrc_irq_control(&mut self, value: u8) {
+        self.irq_enable_after_ack = value & 1 != 0;
+        self.irq_enabled = value & 2 != 0;
+        self.irq_cycle_mode = value & 4 != 0;
+        if self.irq_enabled {
+            self.irq_counter = self.irq_latch;
+            self.irq_prescaler = 341;
+        }
+        self.irq = false;
+    }
+
+    // $F002
+    fn vrc_irq_ack(&mut self) {
+        self.irq = false;
+        self.irq_enabled = self.irq_enable_after_ack;
+    }
 }
 
 // not sure how I'm going to do more complex mppers here...
@@ -254,6 +316,13 @@ impl Cart {
                 prg_bank_C000 = (1024 * 16) * (rom_banks as usize - 1);
                 prg_bank_E000 = ((1024 * 16) * (rom_banks as usize - 1)) + 0x2000;
             }
+            24 | 26 => {
+                // only the last 8kb is fixed
+                prg_bank_8000 = 0x0000;
+                prg_bank_A000 = 0x2000;
+                prg_bank_C000 = 0x4000;
+                prg_bank_E000 = ((1024 * 16) * rom_banks as usize) - 0x2000;
+            }
             _ => {panic!("Mapper {} not supported", mapper)}
 
         }
@@ -282,6 +351,16 @@ impl Cart {
         }
     }
 
+    // The sound chip on the board, if it has one. VRC7 (85), MMC5 (5),
+    // N163 (19) and Sunsoft 5B (69) would go here too.
+    pub fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        match self.mapper {
+            24 => Some(Box::new(Vrc6::new(false))),
+            26 => Some(Box::new(Vrc6::new(true))),
+            _ => None,
+        }
+    }
+
     pub fn write_cart_u8(&mut self, addr: u16, value: u8, chr: &mut ChrRom) {
         self.last_write_addr = addr;
 
@@ -301,6 +380,7 @@ impl Cart {
                     },
                     3 => chr.switch_8kb_bank(value & 0xF),
                     4 => self.mmc3_write(addr, value, chr),
+                    24 | 26 => self.vrc6_write(addr, value, chr),
                     _ => panic!("Mapper {} is unimplemented", self.mapper),
                 }
             }
@@ -422,6 +502,41 @@ impl Cart {
         }
     }
 
+    // Konami VRC6, mapper 26 has A0 and A1 swapped. The sound registers
+    // at $9000-$B002 go to the APU's expansion chip.
+    fn vrc6_write (&mut self, addr: u16, value: u8, chr: &mut ChrRom) {
+        let addr = if self.mapper == 26 {
+            (addr & 0xF000) | (addr & 1) << 1 | (addr & 2) >> 1
+        } else {
+            addr & 0xF003
+        };
+        let prg_banks = self.prg_rom_banks as usize;
+        let chr_banks = (self.chr_rom_banks as usize * 8).max(1);
+        match addr {
+            0x8000..=0x8003 => self.set_16kb_prg_bank((value as usize & 0x0F) % prg_banks, true),
+            0xC000..=0xC003 => self.set_8kb_prg_bank((value as usize & 0x1F) % (prg_banks * 2), 2),
+            // only the CHR mode every game uses, eight 1kb banks
+            0xD000..=0xD003 => {
+                chr.switch_1kb_bank((value as usize % chr_banks) as u8, (addr & 3) as u8)
+            }
+            0xE000..=0xE003 => {
+                chr.switch_1kb_bank((value as usize % chr_banks) as u8, 4 + (addr & 3) as u8)
+            }
+            0xB003 => {
+                let mirroring = (value >> 2) & 3;
+                self.vertical_mirroring = mirroring == 0;
+                self.horizontal_mirroring = mirroring == 1;
+                chr.vertical_mirroring = self.vertical_mirroring;
+                chr.horizontal_mirroring = self.horizontal_mirroring;
+                chr.one_screen_upper = mirroring == 3;
+            }
+            0xF000 => chr.irq_latch = value,
+            0xF001 => chr.vrc_irq_control(value),
+            0xF002 => chr.vrc_irq_ack(),
+            _ => {}
+        }
+    }
+
     fn mmc1_write (&mut self, addr: u16, value: u8, chr: &mut ChrRom) {
         // 0 = load register
         // 1 = control register
@@ -579,3 +694,97 @@ impl fmt::Debug for Cart {
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // 64kb of PRG and 16kb of CHR, every 8kb PRG bank and 1kb CHR bank
+    // filled with its own number
+    fn vrc6_rom(mapper: u8) -> (Cart, ChrRom) {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 4, 2, (mapper & 0x0F) << 4, mapper & 0xF0];
+        rom.resize(INES_OFFSET, 0);
+        for bank in 0..8 {
+            rom.extend_from_slice(&[bank; 0x2000]);
+        }
+        for bank in 0..16 {
+            rom.extend_from_slice(&[bank; 0x400]);
+        }
+        let path = ::std::env::temp_dir().join(format!("vrc6_{}.nes", mapper));
+        File::create(&path).unwrap().write_all(&rom).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        (Cart::new(&path), ChrRom::new(&path))
+    }
+
+    #[test]
+    fn vrc6_prg_banks() {
+        let (mut cart, mut chr) = vrc6_rom(24);
+        assert_eq!(cart.read_cart_u8(0xE000), 7);
+        cart.write_cart_u8(0x8000, 2, &mut chr);
+        assert_eq!(cart.read_cart_u8(0x8000), 4);
+        assert_eq!(cart.read_cart_u8(0xA000), 5);
+        cart.write_cart_u8(0xC000, 3, &mut chr);
+        assert_eq!(cart.read_cart_u8(0xC000), 3);
+        assert_eq!(cart.read_cart_u8(0xFFFF), 7);
+    }
+
+    #[test]
+    fn vrc6_chr_banks_and_mirroring() {
+        let (mut cart, mut chr) = vrc6_rom(24);
+        cart.write_cart_u8(0xD001, 9, &mut chr);
+        cart.write_cart_u8(0xE003, 15, &mut chr);
+        assert_eq!(chr.read_u8(0x0400), 9);
+        assert_eq!(chr.read_u8(0x1C00), 15);
+
+        cart.write_cart_u8(0xB003, 0x04, &mut chr);
+        assert!(chr.horizontal_mirroring && !chr.vertical_mirroring);
+        cart.write_cart_u8(0xB003, 0x0C, &mut chr);
+        assert!(!chr.horizontal_mirroring && !chr.vertical_mirroring);
+        assert!(chr.one_screen_upper);
+        cart.write_cart_u8(0xB003, 0x00, &mut chr);
+        assert!(chr.vertical_mirroring && !chr.one_screen_upper);
+    }
+
+    #[test]
+    fn vrc6_swapped_lines() {
+        // $D002 is the second CHR bank on mapper 26
+        let (mut cart, mut chr) = vrc6_rom(26);
+        cart.write_cart_u8(0xD002, 9, &mut chr);
+        assert_eq!(chr.read_u8(0x0400), 9);
+        cart.write_cart_u8(0xD001, 12, &mut chr);
+        assert_eq!(chr.read_u8(0x0800), 12);
+    }
+
+    #[test]
+    fn vrc6_irq_cycle_mode() {
+        let (mut cart, mut chr) = vrc6_rom(24);
+        cart.write_cart_u8(0xF000, 0xFD, &mut chr);
+        cart.write_cart_u8(0xF001, 0x06, &mut chr);
+        assert!(!chr.cpu_clock(2));
+        assert!(chr.cpu_clock(1));
+        // reloaded from the latch
+        assert_eq!(chr.irq_counter, 0xFD);
+
+        // acknowledging without the A bit set stops it
+        cart.write_cart_u8(0xF002, 0, &mut chr);
+        assert!(!chr.cpu_clock(1000));
+    }
+
+    #[test]
+    fn vrc6_irq_scanline_mode() {
+        // a scanline is 113 2/3 CPU cycles
+        let (mut cart, mut chr) = vrc6_rom(24);
+        cart.write_cart_u8(0xF000, 0xFE, &mut chr);
+        cart.write_cart_u8(0xF001, 0x03, &mut chr);
+        assert!(!chr.cpu_clock(113));
+        assert!(!chr.cpu_clock(1));
+        assert!(!chr.cpu_clock(113));
+        assert!(chr.cpu_clock(1));
+
+        // with the A bit set it carries on after the acknowledge
+        cart.write_cart_u8(0xF002, 0, &mut chr);
+        assert!(!chr.irq);
+        assert!(chr.cpu_clock(341));
+    }
+}
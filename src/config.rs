@@ -0,0 +1,426 @@
+// The settings file, TOML read with the toml crate. Settings are looked up
+// by section path, so ["rom", "smb.nes"] is the [rom."smb.nes"] table.
+// Saving only rewrites the settings that were set, the rest of the file
+// and its comments stay as they were.
+use std::fs::File;
+use std::io::{Read, Write};
+
+pub use toml::Value;
+use toml::value::Table;
+
+pub struct Config {
+    root: Table,
+    // the file as it was loaded
+    text: String,
+    // (section, key) for everything set since
+    changed: Vec<(Vec<String>, String)>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            root: Table::new(),
+            text: String::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Config, String> {
+        let mut file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        match text.parse::<Value>() {
+            Ok(Value::Table(root)) => Ok(Config {
+                root,
+                text: text.to_string(),
+                changed: Vec::new(),
+            }),
+            Ok(_) => Err(String::from("not a table")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let text = self.to_text();
+        let mut file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        file.write_all(text.as_bytes()).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // The loaded text with every changed key's lines replaced. Keys that
+    // weren't in the file go at the end of their section, and sections
+    // that weren't either go at the end of the file.
+    fn to_text(&self) -> String {
+        let old: Vec<&str> = self.text.lines().collect();
+        let mut lines = Vec::new();
+        let mut section = Vec::new();
+        // where each section's last setting is, to add keys after
+        let mut section_ends: Vec<(Vec<String>, usize)> = vec![(Vec::new(), 0)];
+        let mut written = Vec::new();
+        let mut n = 0;
+        while n < old.len() {
+            if let Some(path) = header_path(old[n]) {
+                lines.push(old[n].to_string());
+                section = path;
+                section_ends.push((section.clone(), lines.len()));
+                n += 1;
+                continue;
+            }
+            let (key, count) = match setting_at(&old[n..]) {
+                Some(setting) => setting,
+                None => {
+                    lines.push(old[n].to_string());
+                    n += 1;
+                    continue;
+                }
+            };
+            let change = (section.clone(), key);
+            if self.changed.contains(&change) {
+                lines.push(self.setting_line(&change.0, &change.1));
+                written.push(change);
+            } else {
+                lines.extend(old[n..n + count].iter().map(|line| line.to_string()));
+            }
+            if let Some(last) = section_ends.last_mut() {
+                last.1 = lines.len();
+            }
+            n += count;
+        }
+
+        let mut added: Vec<(Vec<String>, Vec<String>)> = Vec::new();
+        for change in &self.changed {
+            if written.contains(change) {
+                continue;
+            }
+            let line = self.setting_line(&change.0, &change.1);
+            match added.iter_mut().find(|(path, _)| *path == change.0) {
+                Some((_, new_lines)) => new_lines.push(line),
+                None => added.push((change.0.clone(), vec![line])),
+            }
+        }
+
+        // later sections first so the earlier line numbers stay put
+        let mut appended = Vec::new();
+        let mut inserts = Vec::new();
+        for (path, new_lines) in added {
+            match section_ends.iter().find(|(section, _)| *section == path) {
+                Some(&(_, end)) => inserts.push((end, new_lines)),
+                None => appended.push((path, new_lines)),
+            }
+        }
+        inserts.sort_by_key(|&(end, _)| ::std::cmp::Reverse(end));
+        for (end, new_lines) in inserts {
+            for (n, line) in new_lines.into_iter().enumerate() {
+                lines.insert(end + n, line);
+            }
+        }
+        for (path, new_lines) in appended {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(header_line(&path));
+            lines.extend(new_lines);
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    fn setting_line(&self, section: &[String], key: &str) -> String {
+        let names: Vec<&str> = section.iter().map(|name| name.as_str()).collect();
+        let mut table = Table::new();
+        if let Some(value) = self.get(&names, key) {
+            table.insert(key.to_string(), value.clone());
+        }
+        ::toml::to_string(&table).unwrap_or_default().trim_end().to_string()
+    }
+
+    // creates the section, and any sections above it, as needed
+    pub fn set(&mut self, section: &[String], key: &str, value: Value) {
+        let mut table = &mut self.root;
+        for name in section {
+            let entry = table.entry(name.clone()).or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            table = match *entry {
+                Value::Table(ref mut table) => table,
+                _ => unreachable!(),
+            };
+        }
+        table.insert(key.to_string(), value);
+
+        let change = (section.to_vec(), key.to_string());
+        if !self.changed.contains(&change) {
+            self.changed.push(change);
+        }
+    }
+
+    fn section(&self, section: &[&str]) -> Option<&Table> {
+        let mut table = &self.root;
+        for name in section {
+            table = match table.get(*name) {
+                Some(Value::Table(table)) => table,
+                _ => return None,
+            };
+        }
+        Some(table)
+    }
+
+    pub fn get(&self, section: &[&str], key: &str) -> Option<&Value> {
+        self.section(section).and_then(|table| table.get(key))
+    }
+
+    pub fn get_int(&self, section: &[&str], key: &str) -> Option<i64> {
+        self.get(section, key).and_then(Value::as_integer)
+    }
+
+    // whole numbers are fine too
+    pub fn get_float(&self, section: &[&str], key: &str) -> Option<f64> {
+        match self.get(section, key) {
+            Some(&Value::Float(value)) => Some(value),
+            Some(&Value::Integer(value)) => Some(value as f64),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self, section: &[&str], key: &str) -> Option<bool> {
+        self.get(section, key).and_then(Value::as_bool)
+    }
+
+    pub fn get_str(&self, section: &[&str], key: &str) -> Option<&str> {
+        self.get(section, key).and_then(Value::as_str)
+    }
+}
+
+// the section path of a [header] line
+fn header_path(line: &str) -> Option<Vec<String>> {
+    let trimmed = line.trim_start();
+    if !trimmed.starts_with('[') || trimmed.starts_with("[[") {
+        return None;
+    }
+    let mut table = match line.parse::<Value>() {
+        Ok(Value::Table(table)) => table,
+        _ => return None,
+    };
+    let mut path = Vec::new();
+    while let Some((name, value)) = table.into_iter().next() {
+        path.push(name);
+        table = match value {
+            Value::Table(table) => table,
+            _ => return None,
+        };
+    }
+    Some(path)
+}
+
+// the key of a key = value, which can span lines
+fn line_key(text: &str) -> Option<String> {
+    if text.trim_start().starts_with('[') {
+        return None;
+    }
+    match text.parse::<Value>() {
+        Ok(Value::Table(table)) => {
+            match table.into_iter().next() {
+                Some((key, ref value)) if !value.is_table() => Some(key),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// The key of the setting starting at the first line and how many lines
+// it takes up, more than one for arrays and strings split over lines.
+fn setting_at(lines: &[&str]) -> Option<(String, usize)> {
+    let first = lines.first()?.trim_start();
+    if first.is_empty() || first.starts_with('#') || first.starts_with('[') {
+        return None;
+    }
+    let mut text = String::new();
+    for (n, line) in lines.iter().enumerate() {
+        text.push_str(line);
+        text.push('\n');
+        if let Some(key) = line_key(&text) {
+            return Some((key, n + 1));
+        }
+    }
+    None
+}
+
+fn header_line(section: &[String]) -> String {
+    let names: Vec<String> = section.iter().map(|name| {
+        let bare = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if bare {
+            name.clone()
+        } else {
+            Value::String(name.clone()).to_string()
+        }
+    }).collect();
+    format!("[{}]", names.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_and_quoted_sections() {
+        let config = Config::parse("[video]\naspect = \"8:7\"\n\n\
+                                    [rom.\"smb.nes\"] # per game\noverscan_top = 16\n").unwrap();
+        assert_eq!(config.get_str(&["video"], "aspect"), Some("8:7"));
+        assert_eq!(config.get_int(&["rom", "smb.nes"], "overscan_top"), Some(16));
+        assert_eq!(config.get_int(&["rom"], "overscan_top"), None);
+    }
+
+    #[test]
+    fn types_are_checked() {
+        let config = Config::parse("[mixer.noise]\nvolume = 1\npan = -0.5\nenabled = false\n").unwrap();
+        assert_eq!(config.get_float(&["mixer", "noise"], "volume"), Some(1.0));
+        assert_eq!(config.get_float(&["mixer", "noise"], "pan"), Some(-0.5));
+        assert_eq!(config.get_bool(&["mixer", "noise"], "enabled"), Some(false));
+        assert_eq!(config.get_str(&["mixer", "noise"], "enabled"), None);
+        assert_eq!(config.get_int(&["mixer", "noise"], "pan"), None);
+    }
+
+    #[test]
+    fn accepts_the_rest_of_toml() {
+        let config = Config::parse("a = [1, 2]\nb = { c = 3 }\nd = '''\nmulti\n'''\n\
+                                    [video]\noverscan_top = 8\n").unwrap();
+        assert_eq!(config.get_int(&["b"], "c"), Some(3));
+        assert_eq!(config.get_int(&["video"], "overscan_top"), Some(8));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Config::parse("[video\n").is_err());
+        assert!(Config::parse("[a]\nb = 1\nb = 2\n").is_err());
+    }
+
+    #[test]
+    fn set_creates_sections() {
+        let mut config = Config::parse("[input]\nblock_opposite = true\n").unwrap();
+        let section = vec![String::from("input"), String::from("player1")];
+        config.set(&section, "a", Value::String(String::from("Left Ctrl")));
+        assert_eq!(config.get_str(&["input", "player1"], "a"), Some("Left Ctrl"));
+        assert_eq!(config.get_bool(&["input"], "block_opposite"), Some(true));
+    }
+
+    fn section(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn untouched_config_saves_as_is() {
+        let text = "# settings\n[video]\naspect = \"8:7\" # pixel aspect\n\n[mixer]\nstereo = true\n";
+        assert_eq!(Config::parse(text).unwrap().to_text(), text);
+    }
+
+    #[test]
+    fn save_only_rewrites_set_keys() {
+        let mut config = Config::parse("# my keys\n[input.player1]\na = \"X\"   # jump\n\
+                                        b = \"Z\"\n\n[video]\n# keep this\naspect = \"8:7\"\n").unwrap();
+        config.set(&section(&["input", "player1"]), "a", Value::String(String::from("Left Ctrl")));
+        config.set(&section(&["input", "player1"]), "start", Value::String(String::from("Return")));
+        config.set(&section(&["input", "hotkeys"]), "quit", Value::String(String::from("Escape")));
+        let text = config.to_text();
+        assert_eq!(text, "# my keys\n[input.player1]\na = \"Left Ctrl\"\nb = \"Z\"\nstart = \"Return\"\n\n\
+                          [video]\n# keep this\naspect = \"8:7\"\n\n\
+                          [input.hotkeys]\nquit = \"Escape\"\n");
+
+        let reloaded = Config::parse(&text).unwrap();
+        assert_eq!(reloaded.get_str(&["input", "player1"], "a"), Some("Left Ctrl"));
+        assert_eq!(reloaded.get_str(&["input", "player1"], "start"), Some("Return"));
+        assert_eq!(reloaded.get_str(&["input", "hotkeys"], "quit"), Some("Escape"));
+        assert_eq!(reloaded.get_str(&["video"], "aspect"), Some("8:7"));
+    }
+
+    #[test]
+    fn save_quotes_section_names() {
+        let mut config = Config::new();
+        config.set(&section(&["gamepad", "03000000de280000"]), "a", Value::String(String::from("b")));
+        config.set(&section(&["rom", "smb.nes"]), "overscan_top", Value::Integer(16));
+        let text = config.to_text();
+        assert_eq!(text, "[gamepad.03000000de280000]\na = \"b\"\n\n[rom.\"smb.nes\"]\noverscan_top = 16\n");
+        assert_eq!(Config::parse(&text).unwrap().get_int(&["rom", "smb.nes"], "overscan_top"), Some(16));
+    }
+
+    #[test]
+    fn header_and_key_lines() {
+        assert_eq!(header_path("[video]"), Some(section(&["video"])));
+        assert_eq!(header_path("[rom.\"x.nes\"] # per game"), Some(section(&["rom", "x.nes"])));
+        assert_eq!(header_path("[[list]]"), None);
+        assert_eq!(header_path("a = 1"), None);
+        assert_eq!(line_key("a = 1 # note"), Some(String::from("a")));
+        assert_eq!(line_key("\"x.nes\" = 2"), Some(String::from("x.nes")));
+        assert_eq!(line_key("# a = 1"), None);
+        assert_eq!(line_key("[video]"), None);
+        assert_eq!(line_key("a = ["), None);
+    }
+
+    #[test]
+    fn save_keeps_comments() {
+        let mut config = Config::parse("# top
+[video] # the picture
+# above
+                                        aspect = \"8:7\" # inline
+# below
+").unwrap();
+        config.set(&section(&["video"]), "aspect", Value::String(String::from("1:1")));
+        assert_eq!(config.to_text(), "# top\n[video] # the picture\n# above\n\
+                                       aspect = \"1:1\"\n# below\n");
+    }
+
+    #[test]
+    fn save_replaces_keys_in_quoted_sections() {
+        let mut config = Config::parse("[rom.\"x.nes\"]\noverscan_top = 8\n\n\
+                                        [rom.\"y.nes\"]\noverscan_top = 8\n").unwrap();
+        config.set(&section(&["rom", "x.nes"]), "overscan_top", Value::Integer(16));
+        assert_eq!(config.to_text(), "[rom.\"x.nes\"]\noverscan_top = 16\n\n\
+                                       [rom.\"y.nes\"]\noverscan_top = 8\n");
+    }
+
+    #[test]
+    fn save_adds_keys_to_existing_sections() {
+        let mut config = Config::parse("[rom.\"x.nes\"]\noverscan_top = 8\n\n[video]\naspect = \"8:7\"\n").unwrap();
+        config.set(&section(&["rom", "x.nes"]), "overscan_bottom", Value::Integer(8));
+        assert_eq!(config.to_text(), "[rom.\"x.nes\"]\noverscan_top = 8\noverscan_bottom = 8\n\n\
+                                       [video]\naspect = \"8:7\"\n");
+    }
+
+    #[test]
+    fn save_appends_new_sections() {
+        let mut config = Config::parse("[video]\naspect = \"8:7\"\n# last line\n").unwrap();
+        config.set(&section(&["rom", "x.nes"]), "overscan_top", Value::Integer(16));
+        let text = config.to_text();
+        assert_eq!(text, "[video]\naspect = \"8:7\"\n# last line\n\n[rom.\"x.nes\"]\noverscan_top = 16\n");
+        assert_eq!(Config::parse(&text).unwrap().get_int(&["rom", "x.nes"], "overscan_top"), Some(16));
+    }
+
+    #[test]
+    fn save_handles_multi_line_values() {
+        let text = "[mixer]\nlevels = [\n    1,\n    [2, 3], # nested\n]\nstereo = true\n\
+                    notes = '''\nx = 1\n[not.a.section]\n'''\n";
+        let mut config = Config::parse(text).unwrap();
+        assert_eq!(config.to_text(), text);
+
+        // kept whole when something else in the section changes
+        config.set(&section(&["mixer"]), "stereo", Value::Boolean(false));
+        config.set(&section(&["mixer"]), "rate", Value::Integer(48000));
+        assert_eq!(config.to_text(), "[mixer]\nlevels = [\n    1,\n    [2, 3], # nested\n]\nstereo = false\n\
+                                       notes = '''\nx = 1\n[not.a.section]\n'''\nrate = 48000\n");
+
+        // and replaced whole, not added a second time, when it changes
+        config.set(&section(&["mixer"]), "levels", Value::Array(vec![Value::Integer(4)]));
+        let text = config.to_text();
+        assert_eq!(text, "[mixer]\nlevels = [4]\nstereo = false\n\
+                          notes = '''\nx = 1\n[not.a.section]\n'''\nrate = 48000\n");
+        let reloaded = Config::parse(&text).unwrap();
+        assert_eq!(reloaded.get(&["mixer"], "levels"), Some(&Value::Array(vec![Value::Integer(4)])));
+        assert_eq!(reloaded.get_str(&["mixer"], "notes"), Some("x = 1\n[not.a.section]\n"));
+    }
+}
@@ -1,6 +1,8 @@
 use super::*;
 use mem_map::*;
 use opcodes::*;
+use events::EventKind;
+use std::io;
 // use std::collections::HashSet;
 
 // pub HashMap: ops;
@@ -20,6 +22,17 @@ pub struct CPU {
     pub stack_pointer: u8, // S or SP
 
     pub bus: Bus,
+
+    // interrupts raised by the PPU/APU while DMA had the CPU halted
+    pending_nmi: bool,
+    pending_irq: bool,
+    oam_dma_active: bool,
+    // DMC DMA repeats the read the CPU was halted on
+    last_read: u16,
+    // last byte on the data bus, what unmapped reads see
+    open_bus: u8,
+    // a DMC fetch that halts the data read on the instruction's last cycle
+    dmc_on_read: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -59,6 +72,13 @@ impl CPU {
             program_counter: pc,
             stack_pointer: 0xfd,
             bus: bus,
+
+            pending_nmi: false,
+            pending_irq: false,
+            oam_dma_active: false,
+            last_read: 0,
+            open_bus: 0,
+            dmc_on_read: false,
         }
     }
 
@@ -68,7 +88,9 @@ impl CPU {
         let mut instr = INSTRUCTIONS[op as usize];
 
         let operand = if instr.bytes == 3 {
-            Some(self.cpu_read_u16(pc + 1))
+            let operand = self.cpu_read_u16(pc + 1);
+            self.open_bus = (operand >> 8) as u8;
+            Some(operand)
         } else if instr.bytes == 2 {
             Some(self.cpu_read_u8(pc + 1) as u16)
         } else {
@@ -197,6 +219,7 @@ impl CPU {
         let sr: u8 = self.status_reg.into();
         self.push_stack(sr);
  //       println!("NMI");
+        self.bus.ppu.log_event(EventKind::Nmi);
         let tmp = self.cpu_read_u16(NMI_VECTOR_LOC);
         self.program_counter = tmp;
         // need to add some cycles here..
@@ -207,6 +230,7 @@ impl CPU {
         // println!("IRQ test");
         if !self.status_reg.interrupt_disable {
             // println!("IRQ");
+            self.bus.ppu.log_event(EventKind::Irq);
             let hi = (self.program_counter >> 8) as u8;
             self.push_stack(hi);
             let lo = (0x00ff & self.program_counter) as u8;
@@ -767,34 +791,168 @@ impl CPU {
 
     pub fn cpu_read_u8(&mut self, mut addr: u16) -> u8 {
         // println!("Read {:#X}", addr);
+        if self.dmc_on_read {
+            self.dmc_on_read = false;
+            self.dmc_dma(addr);
+        }
+        self.last_read = addr;
         if addr > 0x2007 && addr < 0x4000 {
             addr = 0x2000 + ((addr - 0x2000) % 8)
         }
-        match addr {
+        let value = match addr {
             RAM_START...RAM_VIRTUAL_END => {
                 let addr = addr % RAM_LEN;
 
                 self.bus.ram[addr as usize]
             }
 
-            PPUCTRL => self.bus.ppu.lastwrite,
-            PPUMASK => self.bus.ppu.lastwrite,
-            OAMADDR => self.bus.ppu.lastwrite,
-            PPUSCROLL => self.bus.ppu.lastwrite,
-            PPUADDR => self.bus.ppu.lastwrite,
+            PPUCTRL => self.bus.ppu.read_open_bus(),
+            PPUMASK => self.bus.ppu.read_open_bus(),
+            OAMADDR => self.bus.ppu.read_open_bus(),
+            PPUSCROLL => self.bus.ppu.read_open_bus(),
+            PPUADDR => self.bus.ppu.read_open_bus(),
             PPUSTATUS => self.bus.ppu.read_ppustatus(),
             PPUDATA => self.bus.ppu.read_ppudata(),
             OAMDATA => self.bus.ppu.read_oamdata(),
 
             SND_CHN => self.bus.apu.read_status_reg(),
-            // TODO: implement joysticks
-            JOY1 => self.bus.joy.read_joy1(),
-            JOY2 => 0,
+            JOY1 => self.bus.joy.read(0),
+            JOY2 => match self.bus.zapper {
+                Some(ref zapper) => zapper.read(&self.bus.ppu),
+                None => self.bus.joy.read(1),
+            },
 
-            EXPANSION_ROM_START...PRG_ROM_END => self.bus.cart.read_cart_u8(addr),
+            EXPANSION_ROM_START..=PRG_ROM_END => {
+                match self.bus.apu.read_expansion(addr) {
+                    Some(value) => value,
+                    None => self.bus.cart.read_cart_u8(addr),
+                }
+            }
 
-            _ => panic!("Invalid read location {:#X}", addr),
+            // $4000-$4013 and $4018-$401F aren't readable, so whatever was
+            // last on the bus is read back, usually the address' high byte
+            _ => self.open_bus,
+        };
+        self.open_bus = value;
+        value
+    }
+
+    // Copies a page to OAM through the normal bus. The CPU halts for a
+    // cycle, plus one more to line up with a get cycle, then alternates
+    // reads and writes for 513 or 514 cycles in total.
+    fn oam_dma(&mut self, page: u8) {
+        // println!("OAMDMA at {:#X}", page);
+        self.oam_dma_active = true;
+        if self.bus.apu.even_cycle() {
+            self.stall(1);
+        } else {
+            self.stall(2);
+        }
+        for i in 0..0x100 {
+            let addr = (page as u16) << 8 | i;
+            let data = self.cpu_read_u8(addr);
+            self.stall(1);
+            self.bus.ppu.write_oamdata(data);
+            self.stall(1);
+            self.dmc_dma(addr);
         }
+        self.oam_dma_active = false;
+    }
+
+    // Runs the APU through an instruction's cycles one at a time. A DMC
+    // fetch asked for partway through halts the CPU on the next cycle: one
+    // of the opcode and operand fetches, the data read on the last cycle,
+    // or the next instruction's opcode fetch if it came on the last cycle.
+    pub fn tick_apu(&mut self, ticks: isize) -> bool {
+        let mut irq = false;
+        for cycle in 0..ticks {
+            irq |= self.bus.apu.tick(1);
+            if self.dmc_on_read || self.bus.apu.dmc_dma_request().is_none() {
+                continue;
+            }
+            if cycle + 2 < ticks {
+                let fetch = self.last_read;
+                self.dmc_dma(fetch);
+            } else if cycle + 2 == ticks {
+                self.dmc_on_read = true;
+            }
+        }
+        irq
+    }
+
+    // a fetch still waiting after the instruction halts the opcode fetch
+    pub fn finish_dmc_dma(&mut self) {
+        self.dmc_on_read = false;
+        let pc = self.program_counter;
+        self.dmc_dma(pc);
+    }
+
+    // Fetches a DMC sample byte if the APU is waiting for one. The CPU's
+    // read at halted_read still happens on the halt cycle and is repeated
+    // on the dummy cycle and, when the fetch has to wait for a get cycle,
+    // an alignment cycle, so 3 or 4 cycles in all. Reads with side effects
+    // ($2007, $4015, $4016/$4017) see every one of them. Between OAM DMA
+    // transfers the fetch takes a get cycle and the realignment after it.
+    fn dmc_dma(&mut self, halted_read: u16) {
+        while let Some(addr) = self.bus.apu.dmc_dma_request() {
+            if self.oam_dma_active {
+                self.stall(1);
+            } else {
+                // halt and dummy
+                for _ in 0..2 {
+                    self.cpu_read_u8(halted_read);
+                    self.stall(1);
+                }
+                // gets are the cycles that leave even_cycle() true
+                if self.bus.apu.even_cycle() {
+                    self.cpu_read_u8(halted_read);
+                    self.stall(1);
+                }
+            }
+            let data = self.cpu_read_u8(addr);
+            self.stall(1);
+            self.bus.apu.dmc_dma_complete(data);
+        }
+    }
+
+    // VGM players fetch samples from their own copy of memory, so the
+    // whole sample goes in the log before the write that starts it
+    fn log_dmc_sample(&mut self) {
+        if let Some((start, len)) = self.bus.apu.vgm_sample_range() {
+            for n in 0..len {
+                let addr = (0x8000 + (start + n) % 0x8000) as u16;
+                let value = self.bus.cart.read_cart_u8(addr);
+                self.bus.apu.log_dmc_byte(addr, value);
+            }
+        }
+    }
+
+    pub fn start_vgm(&mut self, path: &str) -> io::Result<()> {
+        self.bus.apu.start_vgm(path)?;
+        self.log_dmc_sample();
+        self.bus.apu.log_registers();
+        Ok(())
+    }
+
+    // runs the PPU and APU while the CPU is halted
+    fn stall(&mut self, cycles: isize) {
+        self.cycle += cycles * PPU_MULTIPLIER;
+        let (nmi, irq) = self.bus.ppu.tick(cycles * PPU_MULTIPLIER);
+        if self.bus.ppu.extra_cycle {
+            self.cycle += 1;
+            self.bus.ppu.extra_cycle = false;
+        }
+        self.pending_nmi |= nmi;
+        self.pending_irq |= irq;
+        self.pending_irq |= self.bus.apu.tick(cycles);
+    }
+
+    // interrupts that came up during DMA, cleared once taken
+    pub fn take_pending_interrupts(&mut self) -> (bool, bool) {
+        let pending = (self.pending_nmi, self.pending_irq);
+        self.pending_nmi = false;
+        self.pending_irq = false;
+        pending
     }
 
     // The actual 6502 can't read a u16, this is for convenince only
@@ -819,11 +977,18 @@ impl CPU {
     }
 
     fn cpu_write_u8(&mut self, mut addr: u16, value: u8) {
-
+        self.open_bus = value;
         if addr > 0x2007 && addr < 0x4000 {
             addr = 0x2000 + ((addr - 0x2000) % 8)
         }
 
+        match addr {
+            PPUCTRL..=PPUDATA | OAMDMA | JOY1 | PRG_ROM_START..=PRG_ROM_END => {
+                self.bus.ppu.log_event(EventKind::Write(addr, value));
+            }
+            _ => {}
+        }
+
         match addr {
             RAM_START...RAM_VIRTUAL_END => {
                 let addr = addr % RAM_LEN;
@@ -838,7 +1003,7 @@ impl CPU {
 
             PPUCTRL => self.bus.ppu.write_ppuctrl(value),
             PPUMASK => self.bus.ppu.write_ppumask(value),
-            PPUSTATUS => {},
+            PPUSTATUS => self.bus.ppu.write_ppustatus(value),
             OAMADDR => self.bus.ppu.write_oamaddr(value),
             OAMDATA => self.bus.ppu.write_oamdata(value),
             PPUSCROLL => self.bus.ppu.write_ppuscroll(value),
@@ -855,32 +1020,19 @@ impl CPU {
                 self.bus.apu.write(addr, value);
             }
 
-            SND_CHN => self.bus.apu.write_status_reg(value, &self.bus.cart),
-
-            OAMDMA => {
-                // println!("OAMDMA at {:#X}", value);
-                for i in 0..0x100 {
-                    // println!("OAMDMA");
-                    let ramaddr:usize = (value as usize) << 8 | i;
-                    let data = self.bus.ram[ramaddr];
-                    // println!("ramaddr: {:#X} data: {:#X}", ramaddr, data);
-                    self.bus.ppu.write_oamdata(data);
+            SND_CHN => {
+                if value & 0x10 != 0 {
+                    self.log_dmc_sample();
                 }
-
-                self.cycle += 513 * PPU_MULTIPLIER;
-                self.cycle %= 341;
-                self.bus.ppu.cycles = self.cycle;
-                self.bus.ppu.scanline += 5;
-
-                // unlike the ppu the apu actually needs to tick instead of faking it.
-                self.bus.apu.tick(513, &self.bus.cart);
-
-
+                self.bus.apu.write_status_reg(value);
             }
 
+            OAMDMA => self.oam_dma(value),
+
             JOY1 => self.bus.joy.strobe_joy(value),
 
             EXPANSION_ROM_START...PRG_ROM_END => {
+                self.bus.apu.write_expansion(addr, value);
                 self.bus.cart.write_cart_u8(addr, value, &mut self.bus.ppu.chr);
                 // self.bus.ppu.chr.write_mapper(addr, value);
             }
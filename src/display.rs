@@ -0,0 +1,238 @@
+// Overscan cropping and aspect ratio of the final picture
+use config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aspect {
+    // 1:1 pixels
+    Square,
+    // the NES's 8:7 pixel aspect ratio
+    Pixel,
+    // whatever is left after cropping fills a 4:3 TV
+    Tv,
+}
+
+impl Aspect {
+    pub fn parse(name: &str) -> Option<Aspect> {
+        match name {
+            "square" | "1:1" => Some(Aspect::Square),
+            "8:7" => Some(Aspect::Pixel),
+            "4:3" => Some(Aspect::Tv),
+            _ => None,
+        }
+    }
+}
+
+pub struct DisplaySettings {
+    pub overscan: Overscan,
+    pub aspect: Aspect,
+    pub integer_scale: bool,
+}
+
+impl DisplaySettings {
+    pub fn new() -> DisplaySettings {
+        DisplaySettings {
+            overscan: Overscan {
+                top: 0,
+                bottom: 0,
+                left: 0,
+                right: 0,
+            },
+            aspect: Aspect::Square,
+            integer_scale: false,
+        }
+    }
+
+    // [video] holds the global settings, [rom."file name.nes"] can override
+    // the overscan for a single game
+    pub fn from_config(config: &Config, rom_name: &str) -> DisplaySettings {
+        let mut settings = DisplaySettings::new();
+        settings.read_overscan(config, &["video"]);
+        settings.read_overscan(config, &["rom", rom_name]);
+
+        if let Some(name) = config.get_str(&["video"], "aspect") {
+            match Aspect::parse(name) {
+                Some(aspect) => settings.aspect = aspect,
+                None => println!("Unknown aspect ratio {:?} in config", name),
+            }
+        }
+        if let Some(integer_scale) = config.get_bool(&["video"], "integer_scale") {
+            settings.integer_scale = integer_scale;
+        }
+        settings
+    }
+
+    fn read_overscan(&mut self, config: &Config, section: &[&str]) {
+        let side = |key: &str, default: usize| {
+            match config.get_int(section, key) {
+                Some(value) if (0..64).contains(&value) => value as usize,
+                Some(value) => {
+                    println!("Ignoring overscan {} = {}", key, value);
+                    default
+                }
+                None => default,
+            }
+        };
+        self.overscan = Overscan {
+            top: side("overscan_top", self.overscan.top),
+            bottom: side("overscan_bottom", self.overscan.bottom),
+            left: side("overscan_left", self.overscan.left),
+            right: side("overscan_right", self.overscan.right),
+        };
+    }
+
+    // size of the visible picture in NES pixels
+    pub fn cropped_size(&self) -> (usize, usize) {
+        (256 - self.overscan.left - self.overscan.right,
+         240 - self.overscan.top - self.overscan.bottom)
+    }
+
+    // display width / height of the visible picture
+    pub fn aspect_ratio(&self) -> f64 {
+        let (w, h) = self.cropped_size();
+        match self.aspect {
+            Aspect::Square => w as f64 / h as f64,
+            Aspect::Pixel => w as f64 * 8.0 / 7.0 / h as f64,
+            Aspect::Tv => 4.0 / 3.0,
+        }
+    }
+
+    // Crops the overscan off a filtered frame. The filter may have scaled
+    // the picture so the borders are scaled to match.
+    pub fn crop(&self, src: &[u32], w: usize, h: usize, dst: &mut Vec<u32>) -> (usize, usize) {
+        let (x0, x1, y0, y1) = self.crop_bounds(w, h);
+        dst.clear();
+        for row in y0..y1 {
+            dst.extend_from_slice(&src[row * w + x0..row * w + x1]);
+        }
+        (x1 - x0, y1 - y0)
+    }
+
+    fn crop_bounds(&self, w: usize, h: usize) -> (usize, usize, usize, usize) {
+        let x0 = self.overscan.left * w / 256;
+        let x1 = w - self.overscan.right * w / 256;
+        let y0 = self.overscan.top * h / 240;
+        let y1 = h - self.overscan.bottom * h / 240;
+        (x0, x1, y0, y1)
+    }
+
+    // where the picture goes in a window, letterboxed to keep the aspect ratio
+    pub fn dest_rect(&self, window_w: u32, window_h: u32) -> (i32, i32, u32, u32) {
+        let aspect = self.aspect_ratio();
+        let (_, cropped_h) = self.cropped_size();
+
+        let (w, h) = if self.integer_scale {
+            let fit_w = (window_w as f64 / (cropped_h as f64 * aspect)) as u32;
+            let fit_h = window_h / cropped_h as u32;
+            let scale = if fit_w < fit_h {
+                fit_w
+            } else {
+                fit_h
+            };
+            let scale = if scale == 0 {
+                1
+            } else {
+                scale
+            };
+            let h = cropped_h as u32 * scale;
+            ((h as f64 * aspect).round() as u32, h)
+        } else if (window_w as f64) < window_h as f64 * aspect {
+            (window_w, (window_w as f64 / aspect).round() as u32)
+        } else {
+            ((window_h as f64 * aspect).round() as u32, window_h)
+        };
+
+        let x = (window_w as i32 - w as i32) / 2;
+        let y = (window_h as i32 - h as i32) / 2;
+        (x, y, w, h)
+    }
+
+    // The NES pixel under a point in a window, undoing dest_rect and the
+    // overscan crop. None in the letterbox bars.
+    pub fn screen_position(&self, window_w: u32, window_h: u32,
+                           x: i32, y: i32) -> Option<(usize, usize)> {
+        let (dest_x, dest_y, w, h) = self.dest_rect(window_w, window_h);
+        if x < dest_x || y < dest_y || x >= dest_x + w as i32 || y >= dest_y + h as i32 {
+            return None;
+        }
+        let (cropped_w, cropped_h) = self.cropped_size();
+        let col = (x - dest_x) as usize * cropped_w / w as usize;
+        let row = (y - dest_y) as usize * cropped_h / h as usize;
+        Some((col + self.overscan.left, row + self.overscan.top))
+    }
+
+    // window size for a picture scaled up scale times vertically
+    pub fn window_size(&self, scale: usize) -> (u32, u32) {
+        let (_, h) = self.cropped_size();
+        let h = h * scale;
+        ((h as f64 * self.aspect_ratio()).round() as u32, h as u32)
+    }
+
+    // Stretches a cropped frame horizontally to the display aspect ratio,
+    // for anything saved to disk.
+    pub fn aspect_corrected(&self, src: &[u32], w: usize, h: usize) -> (Vec<u32>, usize, usize) {
+        let new_w = (h as f64 * self.aspect_ratio()).round() as usize;
+        let mut out = Vec::with_capacity(new_w * h);
+        for row in 0..h {
+            for col in 0..new_w {
+                out.push(src[row * w + col * w / new_w]);
+            }
+        }
+        (out, new_w, h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screen_position_fills_the_window() {
+        let display = DisplaySettings::new();
+        assert_eq!(display.screen_position(512, 480, 0, 0), Some((0, 0)));
+        assert_eq!(display.screen_position(512, 480, 511, 479), Some((255, 239)));
+        assert_eq!(display.screen_position(512, 480, 257, 100), Some((128, 50)));
+        assert_eq!(display.screen_position(512, 480, 512, 0), None);
+    }
+
+    #[test]
+    fn screen_position_letterbox() {
+        let display = DisplaySettings::new();
+        // 640 wide leaves 64 pixel bars either side of a 512x480 picture
+        assert_eq!(display.screen_position(640, 480, 63, 240), None);
+        assert_eq!(display.screen_position(640, 480, 64, 240), Some((0, 120)));
+        assert_eq!(display.screen_position(640, 480, 575, 240), Some((255, 120)));
+        assert_eq!(display.screen_position(640, 480, 576, 240), None);
+    }
+
+    #[test]
+    fn screen_position_overscan() {
+        let mut display = DisplaySettings::new();
+        display.overscan = Overscan {
+            top: 8,
+            bottom: 8,
+            left: 8,
+            right: 8,
+        };
+        // 240x224 shown at twice the size
+        assert_eq!(display.screen_position(480, 448, 0, 0), Some((8, 8)));
+        assert_eq!(display.screen_position(480, 448, 479, 447), Some((247, 231)));
+    }
+
+    #[test]
+    fn screen_position_pixel_aspect() {
+        let mut display = DisplaySettings::new();
+        display.aspect = Aspect::Pixel;
+        let (x, y, w, h) = display.dest_rect(585, 480);
+        assert_eq!((x, y, w, h), (0, 0, 585, 480));
+        assert_eq!(display.screen_position(585, 480, 584, 0), Some((255, 0)));
+        assert_eq!(display.screen_position(585, 480, 292, 0), Some((127, 0)));
+    }
+}
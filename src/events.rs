@@ -0,0 +1,149 @@
+// Log of register writes and interrupts for the event viewer, each tagged
+// with the scanline and dot it happened on. The log for the last complete
+// frame is kept so it can be drawn as a 341x262 timeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    // $2000-$2007, $4014, $4016 and mapper registers
+    Write(u16, u8),
+    Nmi,
+    Irq,
+    Sprite0Hit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub scanline: i16,
+    pub dot: isize,
+    pub kind: EventKind,
+}
+
+pub const TIMELINE_WIDTH: usize = 341;
+pub const TIMELINE_HEIGHT: usize = 262;
+
+pub struct EventLog {
+    // nothing is recorded unless the viewer is open
+    pub enabled: bool,
+    current: Vec<Event>,
+    pub frame: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> EventLog {
+        EventLog {
+            enabled: false,
+            current: Vec::new(),
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, scanline: i16, dot: isize, kind: EventKind) {
+        if self.enabled {
+            self.current.push(Event {
+                scanline,
+                dot,
+                kind,
+            });
+        }
+    }
+
+    // called as the first visible line starts
+    pub fn end_frame(&mut self) {
+        ::std::mem::swap(&mut self.current, &mut self.frame);
+        self.current.clear();
+    }
+
+    // scanline 0 is the top row, the pre-render line is the last
+    pub fn render(&self, output: &mut [u32], width: usize) {
+        for row in 0..TIMELINE_HEIGHT {
+            for dot in 0..TIMELINE_WIDTH {
+                let visible = row < 240 && (1..=256).contains(&dot);
+                output[row * width + dot] = if visible {
+                    0x303030
+                } else if (241..=260).contains(&row) {
+                    0x101018
+                } else {
+                    0x181818
+                };
+            }
+        }
+
+        for event in &self.frame {
+            let (x, y) = position(event);
+            let color = color(event.kind);
+            // 2x2 markers, clipped at the edges
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    if x + dx < TIMELINE_WIDTH && y + dy < TIMELINE_HEIGHT {
+                        output[(y + dy) * width + x + dx] = color;
+                    }
+                }
+            }
+        }
+    }
+
+    // every event within a couple of pixels of x, y
+    pub fn describe(&self, x: usize, y: usize) -> Vec<String> {
+        self.frame.iter()
+            .filter(|event| {
+                let (ex, ey) = position(event);
+                ex.abs_diff(x) <= 2 && ey.abs_diff(y) <= 2
+            })
+            .map(|event| {
+                let what = match event.kind {
+                    EventKind::Write(addr, value) => {
+                        format!("{} ${:04X} = ${:02X}", register_name(addr), addr, value)
+                    }
+                    EventKind::Nmi => String::from("NMI"),
+                    EventKind::Irq => String::from("IRQ"),
+                    EventKind::Sprite0Hit => String::from("sprite 0 hit"),
+                };
+                format!("SL {} dot {}: {}", event.scanline, event.dot, what)
+            })
+            .collect()
+    }
+}
+
+fn position(event: &Event) -> (usize, usize) {
+    let row = ((event.scanline as isize + TIMELINE_HEIGHT as isize) % TIMELINE_HEIGHT as isize) as usize;
+    let dot = if event.dot < 0 {
+        0
+    } else if event.dot >= TIMELINE_WIDTH as isize {
+        TIMELINE_WIDTH - 1
+    } else {
+        event.dot as usize
+    };
+    (dot, row)
+}
+
+fn color(kind: EventKind) -> u32 {
+    match kind {
+        EventKind::Write(0x2000, _) => 0xFF4040,
+        EventKind::Write(0x2001, _) => 0xFF9A40,
+        EventKind::Write(0x2003, _) | EventKind::Write(0x2004, _) => 0xFFE040,
+        EventKind::Write(0x2005, _) => 0x40FF40,
+        EventKind::Write(0x2006, _) => 0x40E0FF,
+        EventKind::Write(0x2007, _) => 0x4060FF,
+        EventKind::Write(0x4014, _) => 0xB040FF,
+        EventKind::Write(0x4016, _) => 0xFFFFFF,
+        EventKind::Write(_, _) => 0xFF60C0,
+        EventKind::Nmi => 0xA0FFA0,
+        EventKind::Irq => 0xFF40FF,
+        EventKind::Sprite0Hit => 0xFFFF00,
+    }
+}
+
+fn register_name(addr: u16) -> &'static str {
+    match addr {
+        0x2000 => "PPUCTRL",
+        0x2001 => "PPUMASK",
+        0x2002 => "PPUSTATUS",
+        0x2003 => "OAMADDR",
+        0x2004 => "OAMDATA",
+        0x2005 => "PPUSCROLL",
+        0x2006 => "PPUADDR",
+        0x2007 => "PPUDATA",
+        0x4014 => "OAMDMA",
+        0x4016 => "JOY1",
+        _ => "mapper",
+    }
+}
@@ -0,0 +1,23 @@
+// Sound chips on the cartridge. The Famicom's cart slot passes the console's
+// audio out through the cart and back in, so a board can add its own
+// channels at whatever level its resistors give them. A mapper with a chip
+// (VRC6, VRC7, FDS, MMC5, N163, Sunsoft 5B) hands one of these to the APU,
+// which clocks it with the CPU and mixes it in as the expansion channel.
+pub trait ExpansionAudio {
+    // every CPU cycle
+    fn clock(&mut self);
+
+    // all $4020-$FFFF writes, the chip picks out its own registers
+    fn write(&mut self, addr: u16, value: u8);
+
+    // chips with readable registers, ie the N163's sound RAM or the FDS
+    // wave table, return Some for them
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    // The current level on the APU's scale, where a pulse channel at full
+    // volume on its own comes to about 0.15, with the board's mix level
+    // already applied (see vrc6::LEVEL).
+    fn output(&self) -> f32;
+}
@@ -0,0 +1,294 @@
+// Software filters that sit between PPU::screen and the output texture
+use hqx::hq2x;
+use ntsc::NtscFilter;
+use palette::Palette;
+use xbrz::xbrz;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest(usize),
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Xbrz(usize),
+    Crt,
+    Ntsc,
+}
+
+// in the order they're cycled through at runtime
+pub const FILTERS: [Filter; 13] = [
+    Filter::Nearest(1),
+    Filter::Nearest(2),
+    Filter::Nearest(3),
+    Filter::Scale2x,
+    Filter::Scale3x,
+    Filter::Hq2x,
+    Filter::Xbrz(2),
+    Filter::Xbrz(3),
+    Filter::Xbrz(4),
+    Filter::Xbrz(5),
+    Filter::Xbrz(6),
+    Filter::Crt,
+    Filter::Ntsc,
+];
+
+impl Filter {
+    pub fn name(&self) -> String {
+        match *self {
+            Filter::Nearest(n) => format!("{}x", n),
+            Filter::Scale2x => String::from("scale2x"),
+            Filter::Scale3x => String::from("scale3x"),
+            Filter::Hq2x => String::from("hq2x"),
+            Filter::Xbrz(n) => format!("xbrz{}x", n),
+            Filter::Crt => String::from("crt"),
+            Filter::Ntsc => String::from("ntsc"),
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Filter> {
+        for filter in FILTERS.iter() {
+            if filter.name() == name {
+                return Some(*filter);
+            }
+        }
+        None
+    }
+
+    pub fn next(&self) -> Filter {
+        let pos = FILTERS.iter().position(|f| f == self).unwrap_or(0);
+        FILTERS[(pos + 1) % FILTERS.len()]
+    }
+}
+
+pub struct FilterChain {
+    pub filter: Filter,
+    ntsc: NtscFilter,
+    rgb: Vec<u32>,
+
+    pub output: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FilterChain {
+    pub fn new(filter: Filter, ntsc: NtscFilter) -> FilterChain {
+        FilterChain {
+            filter,
+            ntsc,
+            rgb: vec![0; 256 * 240],
+
+            output: vec![0; 256 * 240],
+            width: 256,
+            height: 240,
+        }
+    }
+
+    pub fn process(&mut self, screen: &[[u16; 256]; 240], palette: &Palette, framecount: usize) {
+        if self.filter == Filter::Ntsc {
+            self.ntsc.filter(screen, framecount);
+            self.width = self.ntsc.width;
+            self.height = 240;
+            self.output.clear();
+            self.output.extend_from_slice(&self.ntsc.output);
+            return;
+        }
+
+        for (row, line) in screen.iter().enumerate() {
+            for (col, &pixel) in line.iter().enumerate() {
+                self.rgb[row * 256 + col] = palette.rgb(pixel);
+            }
+        }
+
+        let (w, h) = (256, 240);
+        let scale = match self.filter {
+            Filter::Nearest(n) | Filter::Xbrz(n) => n,
+            Filter::Scale2x | Filter::Hq2x => 2,
+            Filter::Scale3x | Filter::Crt => 3,
+            Filter::Ntsc => unreachable!(),
+        };
+        self.width = w * scale;
+        self.height = h * scale;
+        self.output.resize(self.width * self.height, 0);
+
+        match self.filter {
+            Filter::Nearest(n) => nearest(&self.rgb, w, h, n, &mut self.output),
+            Filter::Scale2x => scale2x(&self.rgb, w, h, &mut self.output),
+            Filter::Scale3x => scale3x(&self.rgb, w, h, &mut self.output),
+            Filter::Hq2x => hq2x(&self.rgb, w, h, &mut self.output),
+            Filter::Xbrz(n) => xbrz(&self.rgb, w, h, n, &mut self.output),
+            Filter::Crt => crt(&self.rgb, w, h, &mut self.output),
+            Filter::Ntsc => unreachable!(),
+        }
+    }
+
+    // how many times the picture is scaled up vertically in the window,
+    // at least 480 lines high
+    pub fn window_scale(&self) -> usize {
+        let factor = 480usize.div_ceil(self.height);
+        self.height * factor / 240
+    }
+}
+
+// source pixel with the edges clamped
+pub fn pixel(src: &[u32], w: usize, h: usize, x: isize, y: isize) -> u32 {
+    let x = if x < 0 {
+        0
+    } else if x >= w as isize {
+        w - 1
+    } else {
+        x as usize
+    };
+    let y = if y < 0 {
+        0
+    } else if y >= h as isize {
+        h - 1
+    } else {
+        y as usize
+    };
+    src[y * w + x]
+}
+
+fn blend(a: u32, b: u32, alpha: f32) -> u32 {
+    let mix = |shift: u32| {
+        let ca = ((a >> shift) & 0xFF) as f32;
+        let cb = ((b >> shift) & 0xFF) as f32;
+        ((ca + (cb - ca) * alpha + 0.5) as u32) << shift
+    };
+    mix(16) | mix(8) | mix(0)
+}
+
+fn nearest(src: &[u32], w: usize, h: usize, n: usize, dst: &mut [u32]) {
+    for y in 0..h * n {
+        for x in 0..w * n {
+            dst[y * w * n + x] = src[(y / n) * w + x / n];
+        }
+    }
+}
+
+// EPX / AdvMAME2x
+fn scale2x(src: &[u32], w: usize, h: usize, dst: &mut [u32]) {
+    let dw = w * 2;
+    for y in 0..h {
+        for x in 0..w {
+            let (xi, yi) = (x as isize, y as isize);
+            let b = pixel(src, w, h, xi, yi - 1);
+            let d = pixel(src, w, h, xi - 1, yi);
+            let e = pixel(src, w, h, xi, yi);
+            let f = pixel(src, w, h, xi + 1, yi);
+            let hh = pixel(src, w, h, xi, yi + 1);
+
+            let mut out = [e; 4];
+            if b != hh && d != f {
+                if d == b {
+                    out[0] = d;
+                }
+                if b == f {
+                    out[1] = f;
+                }
+                if d == hh {
+                    out[2] = d;
+                }
+                if hh == f {
+                    out[3] = f;
+                }
+            }
+            let pos = (y * 2) * dw + x * 2;
+            dst[pos] = out[0];
+            dst[pos + 1] = out[1];
+            dst[pos + dw] = out[2];
+            dst[pos + dw + 1] = out[3];
+        }
+    }
+}
+
+// AdvMAME3x
+fn scale3x(src: &[u32], w: usize, h: usize, dst: &mut [u32]) {
+    let dw = w * 3;
+    for y in 0..h {
+        for x in 0..w {
+            let (xi, yi) = (x as isize, y as isize);
+            let a = pixel(src, w, h, xi - 1, yi - 1);
+            let b = pixel(src, w, h, xi, yi - 1);
+            let c = pixel(src, w, h, xi + 1, yi - 1);
+            let d = pixel(src, w, h, xi - 1, yi);
+            let e = pixel(src, w, h, xi, yi);
+            let f = pixel(src, w, h, xi + 1, yi);
+            let g = pixel(src, w, h, xi - 1, yi + 1);
+            let hh = pixel(src, w, h, xi, yi + 1);
+            let i = pixel(src, w, h, xi + 1, yi + 1);
+
+            let mut out = [e; 9];
+            if b != hh && d != f {
+                if d == b {
+                    out[0] = d;
+                }
+                if (d == b && e != c) || (b == f && e != a) {
+                    out[1] = b;
+                }
+                if b == f {
+                    out[2] = f;
+                }
+                if (d == b && e != g) || (d == hh && e != a) {
+                    out[3] = d;
+                }
+                if (b == f && e != i) || (hh == f && e != c) {
+                    out[5] = f;
+                }
+                if d == hh {
+                    out[6] = d;
+                }
+                if (d == hh && e != i) || (hh == f && e != g) {
+                    out[7] = hh;
+                }
+                if hh == f {
+                    out[8] = f;
+                }
+            }
+            for row in 0..3 {
+                for col in 0..3 {
+                    dst[(y * 3 + row) * dw + x * 3 + col] = out[row * 3 + col];
+                }
+            }
+        }
+    }
+}
+
+// 3x output: each source line becomes two lit rows and one dark row, with
+// an RGB aperture mask across the columns and a touch of horizontal blur
+fn crt(src: &[u32], w: usize, h: usize, dst: &mut [u32]) {
+    const ROW_BRIGHTNESS: [f32; 3] = [1.15, 1.05, 0.45];
+    const MASK: [[f32; 3]; 3] = [
+        [1.0, 0.75, 0.75],
+        [0.75, 1.0, 0.75],
+        [0.75, 0.75, 1.0],
+    ];
+
+    let dw = w * 3;
+    for y in 0..h {
+        for x in 0..w * 3 {
+            // sample between source pixels for the outer thirds
+            let sx = x / 3;
+            let e = src[y * w + sx];
+            let color = match x % 3 {
+                0 => blend(e, pixel(src, w, h, sx as isize - 1, y as isize), 0.25),
+                2 => blend(e, pixel(src, w, h, sx as isize + 1, y as isize), 0.25),
+                _ => e,
+            };
+            let mask = MASK[x % 3];
+
+            for row in 0..3 {
+                let scale = |shift: u32, m: f32| {
+                    let value = ((color >> shift) & 0xFF) as f32 * m * ROW_BRIGHTNESS[row];
+                    let value = if value > 255.0 {
+                        255
+                    } else {
+                        value as u32
+                    };
+                    value << shift
+                };
+                dst[(y * 3 + row) * dw + x] = scale(16, mask[0]) | scale(8, mask[1]) |
+                                              scale(0, mask[2]);
+            }
+        }
+    }
+}
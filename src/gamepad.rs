@@ -0,0 +1,341 @@
+// Game controllers through SDL's GameController API, which gives every
+// supported pad the same Xbox style layout. Pads are opened as they're
+// plugged in and handed to the first player without one, unless a player
+// is pinned to a particular pad's GUID. The buttons each NES button comes
+// from can be set per GUID, and the left stick works as the d-pad once
+// it's pushed past the threshold.
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::{GameControllerSubsystem, JoystickSubsystem};
+
+use config::Config;
+use input::{BUTTON_NAMES, PLAYERS, TURBO_NAMES};
+
+// positional, NES B and A are the bottom and right face buttons
+const DEFAULT_MAPPING: [Button; 8] = [
+    Button::B, Button::A, Button::Back, Button::Start,
+    Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight,
+];
+
+// turbo A and B on the top and left face buttons
+const DEFAULT_TURBO: [Button; 2] = [Button::Y, Button::X];
+
+const UP: u8 = 1 << 4;
+const DOWN: u8 = 1 << 5;
+const LEFT: u8 = 1 << 6;
+const RIGHT: u8 = 1 << 7;
+
+struct Pad {
+    controller: GameController,
+    mapping: [Option<Button>; 8],
+    turbo: [Option<Button>; 2],
+}
+
+// Which player each connected pad is, by SDL instance id. A pad goes to
+// the player pinned to its GUID, otherwise the first unpinned player
+// free, and pads left without one wait in the order they were connected.
+struct Players {
+    // [input.playerN] controller = "<guid>"
+    pinned: [Option<String>; PLAYERS],
+    // (instance id, GUID, player) in connection order
+    pads: Vec<(u32, String, Option<usize>)>,
+}
+
+impl Players {
+    fn new(pinned: [Option<String>; PLAYERS]) -> Players {
+        Players {
+            pinned,
+            pads: Vec::new(),
+        }
+    }
+
+    fn player(&self, id: u32) -> Option<usize> {
+        self.pads.iter().find(|pad| pad.0 == id).and_then(|pad| pad.2)
+    }
+
+    // the player the new pad gets
+    fn add(&mut self, id: u32, guid: &str) -> Option<usize> {
+        let player = self.free_player(guid);
+        self.pads.push((id, guid.to_string(), player));
+        player
+    }
+
+    // (instance id, player) for each waiting pad that got the player freed up
+    fn remove(&mut self, id: u32) -> Vec<(u32, usize)> {
+        let n = self.pads.iter().position(|pad| pad.0 == id);
+        if n.and_then(|n| self.pads.remove(n).2).is_some() {
+            self.promote()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn promote(&mut self) -> Vec<(u32, usize)> {
+        let mut promoted = Vec::new();
+        for n in 0..self.pads.len() {
+            if self.pads[n].2.is_some() {
+                continue;
+            }
+            if let Some(player) = self.free_player(&self.pads[n].1) {
+                self.pads[n].2 = Some(player);
+                promoted.push((self.pads[n].0, player));
+            }
+        }
+        promoted
+    }
+
+    // a player pinned to this GUID, otherwise the first unpinned one free
+    fn free_player(&self, guid: &str) -> Option<usize> {
+        let taken = |player: usize| self.pads.iter().any(|pad| pad.2 == Some(player));
+        (0..PLAYERS)
+            .find(|&player| self.pinned[player].as_deref() == Some(guid) &&
+                            !taken(player))
+            .or_else(|| (0..PLAYERS).find(|&player| self.pinned[player].is_none() && !taken(player)))
+    }
+}
+
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    joysticks: JoystickSubsystem,
+    pads: Vec<Pad>,
+    players: Players,
+    // how far the stick has to go, out of 32767
+    threshold: i16,
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem, joysticks: JoystickSubsystem,
+               config: &Config) -> Gamepads {
+        let mut pinned = [None, None];
+        for (player, pin) in pinned.iter_mut().enumerate() {
+            let section = format!("player{}", player + 1);
+            *pin = config.get_str(&["input", &section], "controller")
+                .filter(|guid| !guid.is_empty())
+                .map(|guid| guid.to_lowercase());
+        }
+        Gamepads {
+            subsystem,
+            joysticks,
+            pads: Vec::new(),
+            players: Players::new(pinned),
+            threshold: threshold(config),
+        }
+    }
+
+    // SDL sends an add event for each pad already connected at startup too
+    pub fn add(&mut self, index: u32, config: &Config) {
+        let guid = match self.joysticks.device_guid(index) {
+            Ok(guid) => guid.string().to_lowercase(),
+            Err(_) => return,
+        };
+        let controller = match self.subsystem.open(index) {
+            Ok(controller) => controller,
+            Err(e) => {
+                println!("Couldn't open controller {}: {}", index, e);
+                return;
+            }
+        };
+        if self.pads.iter().any(|pad| pad.controller.instance_id() == controller.instance_id()) {
+            return;
+        }
+
+        match self.players.add(controller.instance_id(), &guid) {
+            Some(player) => println!("{} ({}) is player {}", controller.name(), guid, player + 1),
+            None => println!("{} ({}) connected, no free player", controller.name(), guid),
+        }
+        let mut mapping = [None; 8];
+        for (n, name) in BUTTON_NAMES.iter().enumerate() {
+            mapping[n] = mapped_button(config, &guid, name, DEFAULT_MAPPING[n]);
+        }
+        let mut turbo = [None; 2];
+        for (n, name) in TURBO_NAMES.iter().enumerate() {
+            turbo[n] = mapped_button(config, &guid, name, DEFAULT_TURBO[n]);
+        }
+        self.pads.push(Pad {
+            mapping,
+            turbo,
+            controller,
+        });
+    }
+
+    // by instance id, which isn't the index it was added with
+    pub fn remove(&mut self, id: u32) {
+        if let Some(n) = self.pads.iter().position(|pad| pad.controller.instance_id() == id) {
+            let pad = self.pads.remove(n);
+            println!("{} disconnected", pad.controller.name());
+            for (id, player) in self.players.remove(id) {
+                if let Some(pad) = self.pads.iter().find(|pad| pad.controller.instance_id() == id) {
+                    println!("{} is player {}", pad.controller.name(), player + 1);
+                }
+            }
+        }
+    }
+
+    fn player_pads<'a>(&'a self, player: usize) -> impl Iterator<Item = &'a Pad> + 'a {
+        self.pads.iter()
+            .filter(move |pad| self.players.player(pad.controller.instance_id()) == Some(player))
+    }
+
+    // button bitmask from every pad the player has
+    pub fn buttons(&self, player: usize) -> u8 {
+        let mut buttons = 0;
+        for pad in self.player_pads(player) {
+            for (bit, button) in pad.mapping.iter().enumerate() {
+                if button.is_some_and(|button| pad.controller.button(button)) {
+                    buttons |= 1 << bit;
+                }
+            }
+            buttons |= stick_dpad(pad.controller.axis(Axis::LeftX),
+                                  pad.controller.axis(Axis::LeftY), self.threshold);
+        }
+        buttons
+    }
+
+    // held turbo buttons, like Bindings::turbo
+    pub fn turbo(&self, player: usize) -> u8 {
+        let mut turbo = 0;
+        for pad in self.player_pads(player) {
+            for (bit, button) in pad.turbo.iter().enumerate() {
+                if button.is_some_and(|button| pad.controller.button(button)) {
+                    turbo |= 1 << bit;
+                }
+            }
+        }
+        turbo
+    }
+}
+
+// [gamepad] threshold, out of 32767
+fn threshold(config: &Config) -> i16 {
+    let threshold = match config.get_float(&["gamepad"], "threshold") {
+        Some(threshold) if threshold > 0.0 && threshold < 1.0 => threshold,
+        _ => 0.5,
+    };
+    (threshold * 32767.0) as i16
+}
+
+// d-pad bits for the left stick, up and left are negative
+fn stick_dpad(x: i16, y: i16, threshold: i16) -> u8 {
+    let mut buttons = 0;
+    if y < -threshold {
+        buttons |= UP;
+    } else if y > threshold {
+        buttons |= DOWN;
+    }
+    if x < -threshold {
+        buttons |= LEFT;
+    } else if x > threshold {
+        buttons |= RIGHT;
+    }
+    buttons
+}
+
+// [gamepad."<guid>"] a = "b", turbo_a = "y" ... in SDL's button names
+fn mapped_button(config: &Config, guid: &str, name: &str, default: Button) -> Option<Button> {
+    match config.get_str(&["gamepad", guid], name) {
+        Some("") => None,
+        Some(button) => Button::from_string(button).or_else(|| {
+            println!("Unknown controller button {:?}", button);
+            Some(default)
+        }),
+        None => Some(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned(player1: Option<&str>, player2: Option<&str>) -> Players {
+        Players::new([player1.map(String::from), player2.map(String::from)])
+    }
+
+    #[test]
+    fn pads_go_to_players_in_plug_order() {
+        let mut players = pinned(None, None);
+        assert_eq!(players.add(10, "a"), Some(0));
+        assert_eq!(players.add(11, "a"), Some(1));
+        assert_eq!(players.add(12, "b"), None);
+        assert_eq!(players.player(10), Some(0));
+        assert_eq!(players.player(11), Some(1));
+        assert_eq!(players.player(12), None);
+        assert_eq!(players.player(13), None);
+    }
+
+    #[test]
+    fn pinned_player_waits_for_its_pad() {
+        let mut players = pinned(Some("p"), None);
+        // player 1 stays free for p, so other pads start at player 2
+        assert_eq!(players.add(10, "a"), Some(1));
+        assert_eq!(players.add(11, "b"), None);
+        assert_eq!(players.add(12, "p"), Some(0));
+
+        // a second p pad can still take an unpinned player
+        let mut players = pinned(Some("p"), None);
+        assert_eq!(players.add(10, "p"), Some(0));
+        assert_eq!(players.add(11, "p"), Some(1));
+    }
+
+    #[test]
+    fn pinned_pad_takes_its_player_after_hotplug() {
+        let mut players = pinned(None, Some("p"));
+        assert_eq!(players.add(10, "a"), Some(0));
+        assert_eq!(players.add(11, "b"), None);
+        assert_eq!(players.add(12, "p"), Some(1));
+        // unplugging the pinned pad doesn't hand its player to the waiting one
+        assert_eq!(players.remove(12), vec![]);
+        assert_eq!(players.player(11), None);
+        assert_eq!(players.add(13, "p"), Some(1));
+    }
+
+    #[test]
+    fn waiting_pads_are_promoted_in_plug_order() {
+        let mut players = pinned(None, None);
+        players.add(10, "a");
+        players.add(11, "b");
+        players.add(12, "c");
+        players.add(13, "d");
+        assert_eq!(players.remove(11), vec![(12, 1)]);
+        assert_eq!(players.player(12), Some(1));
+        assert_eq!(players.player(13), None);
+        // pads without a player leave nothing to promote
+        assert_eq!(players.remove(13), vec![]);
+        assert_eq!(players.remove(10), vec![]);
+        assert_eq!(players.add(14, "e"), Some(0));
+    }
+
+    #[test]
+    fn promotion_respects_pins() {
+        let mut players = pinned(Some("p"), None);
+        players.add(10, "a");
+        players.add(11, "p");
+        players.add(12, "b");
+        players.add(13, "p");
+        // player 2 frees up, the first waiting pad gets it whatever its GUID
+        assert_eq!(players.remove(10), vec![(12, 1)]);
+        // player 1 frees up, only the other p pad can have it
+        assert_eq!(players.remove(11), vec![(13, 0)]);
+    }
+
+    #[test]
+    fn stick_past_threshold_presses_the_dpad() {
+        assert_eq!(stick_dpad(0, 0, 16383), 0);
+        assert_eq!(stick_dpad(-16383, 16383, 16383), 0);
+        assert_eq!(stick_dpad(0, -16384, 16383), UP);
+        assert_eq!(stick_dpad(0, 32767, 16383), DOWN);
+        assert_eq!(stick_dpad(-32768, 0, 16383), LEFT);
+        assert_eq!(stick_dpad(20000, 0, 16383), RIGHT);
+        assert_eq!(stick_dpad(-20000, -20000, 16383), UP | LEFT);
+        assert_eq!(stick_dpad(20000, 20000, 16383), DOWN | RIGHT);
+    }
+
+    #[test]
+    fn threshold_from_config() {
+        let config = |text: &str| Config::parse(text).unwrap();
+        assert_eq!(threshold(&Config::new()), 16383);
+        assert_eq!(threshold(&config("[gamepad]\nthreshold = 0.25\n")), 8191);
+        // out of range or the wrong type falls back to half way
+        assert_eq!(threshold(&config("[gamepad]\nthreshold = 1\n")), 16383);
+        assert_eq!(threshold(&config("[gamepad]\nthreshold = \"high\"\n")), 16383);
+        assert_eq!(stick_dpad(9000, 0, threshold(&config("[gamepad]\nthreshold = 0.25\n"))), RIGHT);
+    }
+}
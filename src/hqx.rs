@@ -0,0 +1,208 @@
+// hq2x, Maxim Stepin's magnification filter.
+// Each source pixel is compared with its 8 neighbours in YUV and the
+// resulting 8 bit pattern picks how each of the 4 output pixels mixes the
+// centre with its neighbours. Instead of the original 256 entry switch the
+// patterns are matched with masks, the way FFmpeg's port does it, so only
+// the top left output pixel needs spelling out and the other 3 are the
+// same rules on a mirrored neighbourhood.
+use filters::pixel;
+
+// a neighbour only counts as different past these YUV distances
+const Y_THRESHOLD: i32 = 48;
+const U_THRESHOLD: i32 = 7;
+const V_THRESHOLD: i32 = 6;
+
+// neighbourhood order, 4 is the source pixel
+//   0 1 2
+//   3 4 5
+//   6 7 8
+const TOP_LEFT: [usize; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 8];
+const TOP_RIGHT: [usize; 9] = [2, 1, 0, 5, 4, 3, 8, 7, 6];
+const BOTTOM_LEFT: [usize; 9] = [6, 7, 8, 3, 4, 5, 0, 1, 2];
+const BOTTOM_RIGHT: [usize; 9] = [8, 7, 6, 5, 4, 3, 2, 1, 0];
+
+fn yuv(color: u32) -> (i32, i32, i32) {
+    let r = ((color >> 16) & 0xFF) as f64;
+    let g = ((color >> 8) & 0xFF) as f64;
+    let b = (color & 0xFF) as f64;
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = -0.169 * r - 0.331 * g + 0.5 * b + 128.0;
+    let v = 0.5 * r - 0.419 * g - 0.081 * b + 128.0;
+    (y as i32, u as i32, v as i32)
+}
+
+fn differ(a: u32, b: u32) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() > Y_THRESHOLD || (ua - ub).abs() > U_THRESHOLD ||
+        (va - vb).abs() > V_THRESHOLD
+}
+
+// weighted sum of the colours shifted down, channel by channel
+fn mix(colors: &[(u32, u32)], shift: u32) -> u32 {
+    let channel = |pos: u32| {
+        let sum: u32 = colors.iter().map(|&(c, weight)| ((c >> pos) & 0xFF) * weight).sum();
+        (sum >> shift) << pos
+    };
+    channel(16) | channel(8) | channel(0)
+}
+
+// skips the centre, bit n is neighbour n for 0-3 and n + 1 for 4-7
+fn bit(n: usize) -> usize {
+    if n > 4 {
+        n - 1
+    } else {
+        n
+    }
+}
+
+// Top left output pixel for the neighbourhood w seen through order, which
+// mirrors it so any of the 4 output pixels can be treated as the top left.
+// pattern has a bit set for each neighbour that differs from the centre.
+fn interpolate(w: &[u32; 9], pattern: usize, order: &[usize; 9]) -> u32 {
+    let mut k = 0;
+    for n in (0..9).filter(|&n| n != 4) {
+        k |= ((pattern >> bit(n)) & 1) << bit(order[n]);
+    }
+    let p = |mask: usize, value: usize| k & mask == value;
+
+    let (w0, w1, w3) = (w[order[0]], w[order[1]], w[order[3]]);
+    let (w4, w5, w7) = (w[order[4]], w[order[5]], w[order[7]]);
+
+    let shallow = p(0xBF, 0x37) || p(0xDB, 0x13);
+    let steep = p(0xDB, 0x49) || p(0xEF, 0x6D);
+
+    if shallow && differ(w1, w5) {
+        mix(&[(w4, 3), (w3, 1)], 2)
+    } else if steep && differ(w7, w3) {
+        mix(&[(w4, 3), (w1, 1)], 2)
+    } else if (p(0x0B, 0x0B) || p(0xFE, 0x4A) || p(0xFE, 0x1A)) && differ(w3, w1) {
+        w4
+    } else if (p(0x6F, 0x2A) || p(0x5B, 0x0A) || p(0xBF, 0x3A) || p(0xDF, 0x5A) ||
+               p(0x9F, 0x8A) || p(0xCF, 0x8A) || p(0xEF, 0x4E) || p(0x3F, 0x0E) ||
+               p(0xFB, 0x5A) || p(0xBB, 0x8A) || p(0x7F, 0x5A) || p(0xAF, 0x8A) ||
+               p(0xEB, 0x8A)) && differ(w3, w1) {
+        mix(&[(w4, 3), (w0, 1)], 2)
+    } else if p(0x0B, 0x08) {
+        mix(&[(w4, 2), (w0, 1), (w1, 1)], 2)
+    } else if p(0x0B, 0x02) {
+        mix(&[(w4, 2), (w0, 1), (w3, 1)], 2)
+    } else if p(0x2F, 0x2F) {
+        mix(&[(w4, 14), (w3, 1), (w1, 1)], 4)
+    } else if shallow {
+        mix(&[(w4, 5), (w1, 2), (w3, 1)], 3)
+    } else if steep {
+        mix(&[(w4, 5), (w3, 2), (w1, 1)], 3)
+    } else if p(0x1B, 0x03) || p(0x4F, 0x43) || p(0x8B, 0x83) || p(0x6B, 0x43) {
+        mix(&[(w4, 3), (w3, 1)], 2)
+    } else if p(0x4B, 0x09) || p(0x8B, 0x89) || p(0x1F, 0x19) || p(0x3B, 0x19) {
+        mix(&[(w4, 3), (w1, 1)], 2)
+    } else if p(0x7E, 0x2A) || p(0xEF, 0xAB) || p(0xBF, 0x8F) || p(0x7E, 0x0E) {
+        mix(&[(w4, 2), (w3, 3), (w1, 3)], 3)
+    } else if p(0xFB, 0x6A) || p(0x6F, 0x6E) || p(0x3F, 0x3E) || p(0xFB, 0xFA) ||
+              p(0xDF, 0xDE) || p(0xDF, 0x1E) {
+        mix(&[(w4, 3), (w0, 1)], 2)
+    } else if p(0x0A, 0x00) || p(0x4F, 0x4B) || p(0x9F, 0x1B) || p(0x2F, 0x0B) ||
+              p(0xBE, 0x0A) || p(0xEE, 0x0A) || p(0x7E, 0x0A) || p(0xEB, 0x4B) ||
+              p(0x3B, 0x1B) {
+        mix(&[(w4, 2), (w3, 1), (w1, 1)], 2)
+    } else {
+        mix(&[(w4, 6), (w3, 1), (w1, 1)], 3)
+    }
+}
+
+pub fn hq2x(src: &[u32], w: usize, h: usize, dst: &mut [u32]) {
+    let dw = w * 2;
+    for y in 0..h {
+        for x in 0..w {
+            let (xi, yi) = (x as isize, y as isize);
+            let mut n = [0; 9];
+            for (i, color) in n.iter_mut().enumerate() {
+                let (dx, dy) = ((i % 3) as isize - 1, (i / 3) as isize - 1);
+                *color = pixel(src, w, h, xi + dx, yi + dy);
+            }
+
+            let mut pattern = 0;
+            for i in (0..9).filter(|&i| i != 4) {
+                if n[i] != n[4] && differ(n[i], n[4]) {
+                    pattern |= 1 << bit(i);
+                }
+            }
+
+            let pos = (y * 2) * dw + x * 2;
+            dst[pos] = interpolate(&n, pattern, &TOP_LEFT);
+            dst[pos + 1] = interpolate(&n, pattern, &TOP_RIGHT);
+            dst[pos + dw] = interpolate(&n, pattern, &BOTTOM_LEFT);
+            dst[pos + dw + 1] = interpolate(&n, pattern, &BOTTOM_RIGHT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u32 = 0xFFFFFF;
+    const BLACK: u32 = 0x000000;
+
+    fn scale(src: &[u32], w: usize, h: usize) -> Vec<u32> {
+        let mut dst = vec![0; w * h * 4];
+        hq2x(src, w, h, &mut dst);
+        dst
+    }
+
+    #[test]
+    fn flat_stays_flat() {
+        let src = [0x123456; 9];
+        assert!(scale(&src, 3, 3).iter().all(|&c| c == 0x123456));
+    }
+
+    #[test]
+    fn lone_pixel() {
+        // every neighbour differs (pattern 255) and they're all alike, so
+        // each corner keeps 14/16 of the centre
+        let mut src = [BLACK; 9];
+        src[4] = WHITE;
+        let dst = scale(&src, 3, 3);
+        for &pos in [14, 15, 20, 21].iter() {
+            assert_eq!(dst[pos], 0xDFDFDF);
+        }
+        // and nothing bleeds into the neighbours
+        let lit = dst.iter().filter(|&&c| c != BLACK).count();
+        assert_eq!(lit, 4);
+    }
+
+    #[test]
+    fn inside_corner() {
+        // the centre's top and left neighbours differ, the corner between
+        // them doesn't (pattern 10)
+        let src = [
+            WHITE, BLACK, WHITE,
+            BLACK, WHITE, WHITE,
+            WHITE, WHITE, WHITE,
+        ];
+        let dst = scale(&src, 3, 3);
+        // alike edges: 2/4 centre and 1/4 each of the two edges
+        assert_eq!(dst[14], 0x7F7F7F);
+        assert_eq!(dst[15], WHITE);
+        assert_eq!(dst[20], WHITE);
+        assert_eq!(dst[21], WHITE);
+
+        // edges that differ from each other: 3/4 centre, 1/4 corner
+        let mut src = src;
+        src[3] = 0xFF0000;
+        let dst = scale(&src, 3, 3);
+        assert_eq!(dst[14], WHITE);
+    }
+
+    #[test]
+    fn close_colours_count_as_the_same() {
+        // under all three thresholds
+        assert!(!differ(0x808080, 0x909090));
+        assert!(differ(0x000000, 0x404040));
+        let mut src = [0x808080; 9];
+        src[4] = 0x909090;
+        // so it's pattern 0, 2/4 centre and 1/4 each of the two edges
+        assert!(scale(&src, 3, 3)[14..16].iter().all(|&c| c == 0x888888));
+    }
+}
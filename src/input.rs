@@ -0,0 +1,285 @@
+// Maps keys to controller buttons and emulator hotkeys, so nothing past
+// here needs to know about SDL. Bindings are SDL key names under
+// [input.player1], [input.player2] and [hotkeys] in the config file, and
+// can all be changed while running with the rebind hotkey.
+use sdl2::keyboard::Keycode;
+
+use config::{Config, Value};
+
+// bit order of the controller's report
+pub const BUTTON_NAMES: [&str; 8] = ["a", "b", "select", "start", "up", "down", "left", "right"];
+// autofire A and B, same bits as the buttons they fire
+pub const TURBO_NAMES: [&str; 2] = ["turbo_a", "turbo_b"];
+pub const PLAYERS: usize = 2;
+// buttons then turbo buttons, for the rebinder
+const PLAYER_KEYS: usize = 10;
+
+const DEFAULT_BUTTONS: [[Keycode; 8]; PLAYERS] = [
+    [Keycode::LCtrl, Keycode::LShift, Keycode::Space, Keycode::Return,
+     Keycode::Up, Keycode::Down, Keycode::Left, Keycode::Right],
+    [Keycode::K, Keycode::J, Keycode::U, Keycode::I,
+     Keycode::W, Keycode::S, Keycode::A, Keycode::D],
+];
+
+const DEFAULT_TURBO: [[Keycode; 2]; PLAYERS] = [
+    [Keycode::Z, Keycode::X],
+    [Keycode::O, Keycode::Y],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Quit,
+    ToggleBackground,
+    ToggleSprites,
+    ToggleSpriteLimit,
+    NextPalette,
+    NextFilter,
+    DebugViewer,
+    NextDebugView,
+    NextPatternPalette,
+    AudioStats,
+    RecordAudio,
+    RecordStems,
+    Screenshot,
+    // mixer channel
+    Mute(usize),
+    VgmLog,
+    VgmLoop,
+    SaveState,
+    LoadState,
+    Pause,
+    Rewind,
+    // held down rather than toggled
+    FastForward,
+    Rebind,
+}
+
+// config name and default key
+const HOTKEYS: [(Hotkey, &str, Keycode); 27] = [
+    (Hotkey::Quit, "quit", Keycode::Escape),
+    (Hotkey::ToggleBackground, "toggle_background", Keycode::F1),
+    (Hotkey::ToggleSprites, "toggle_sprites", Keycode::F2),
+    (Hotkey::ToggleSpriteLimit, "toggle_sprite_limit", Keycode::F3),
+    (Hotkey::NextPalette, "next_palette", Keycode::F4),
+    (Hotkey::NextFilter, "next_filter", Keycode::F5),
+    (Hotkey::DebugViewer, "debug_viewer", Keycode::F6),
+    (Hotkey::NextDebugView, "next_debug_view", Keycode::F7),
+    (Hotkey::NextPatternPalette, "next_pattern_palette", Keycode::F8),
+    (Hotkey::AudioStats, "audio_stats", Keycode::F9),
+    (Hotkey::RecordAudio, "record_audio", Keycode::F10),
+    (Hotkey::RecordStems, "record_stems", Keycode::F11),
+    (Hotkey::Screenshot, "screenshot", Keycode::F12),
+    (Hotkey::Mute(0), "mute_pulse1", Keycode::Num1),
+    (Hotkey::Mute(1), "mute_pulse2", Keycode::Num2),
+    (Hotkey::Mute(2), "mute_triangle", Keycode::Num3),
+    (Hotkey::Mute(3), "mute_noise", Keycode::Num4),
+    (Hotkey::Mute(4), "mute_dmc", Keycode::Num5),
+    (Hotkey::Mute(5), "mute_expansion", Keycode::Num6),
+    (Hotkey::VgmLog, "vgm_log", Keycode::V),
+    (Hotkey::VgmLoop, "vgm_loop", Keycode::L),
+    (Hotkey::SaveState, "save_state", Keycode::Insert),
+    (Hotkey::LoadState, "load_state", Keycode::Home),
+    (Hotkey::Pause, "pause", Keycode::P),
+    (Hotkey::Rewind, "rewind", Keycode::Backspace),
+    (Hotkey::FastForward, "fast_forward", Keycode::Tab),
+    (Hotkey::Rebind, "rebind", Keycode::Backquote),
+];
+
+pub struct Bindings {
+    buttons: [[Option<Keycode>; 8]; PLAYERS],
+    turbo: [[Option<Keycode>; 2]; PLAYERS],
+    // same order as HOTKEYS
+    hotkeys: Vec<Option<Keycode>>,
+}
+
+impl Bindings {
+    pub fn new() -> Bindings {
+        let mut buttons = [[None; 8]; PLAYERS];
+        for (player, defaults) in DEFAULT_BUTTONS.iter().enumerate() {
+            for (button, &key) in defaults.iter().enumerate() {
+                buttons[player][button] = Some(key);
+            }
+        }
+        let mut turbo = [[None; 2]; PLAYERS];
+        for (player, defaults) in DEFAULT_TURBO.iter().enumerate() {
+            for (button, &key) in defaults.iter().enumerate() {
+                turbo[player][button] = Some(key);
+            }
+        }
+        Bindings {
+            buttons,
+            turbo,
+            hotkeys: HOTKEYS.iter().map(|&(_, _, key)| Some(key)).collect(),
+        }
+    }
+
+    // anything missing keeps its default, "" unbinds
+    pub fn from_config(config: &Config) -> Bindings {
+        let mut bindings = Bindings::new();
+        for player in 0..PLAYERS {
+            let section = player_section(player);
+            for (button, name) in BUTTON_NAMES.iter().enumerate() {
+                if let Some(key) = config.get_str(&["input", &section], name) {
+                    bindings.buttons[player][button] = parse_key(key);
+                }
+            }
+            for (button, name) in TURBO_NAMES.iter().enumerate() {
+                if let Some(key) = config.get_str(&["input", &section], name) {
+                    bindings.turbo[player][button] = parse_key(key);
+                }
+            }
+        }
+        for (n, &(_, name, _)) in HOTKEYS.iter().enumerate() {
+            if let Some(key) = config.get_str(&["hotkeys"], name) {
+                bindings.hotkeys[n] = parse_key(key);
+            }
+        }
+        bindings
+    }
+
+    pub fn save_to(&self, config: &mut Config) {
+        for player in 0..PLAYERS {
+            let section = vec![String::from("input"), player_section(player)];
+            for (button, name) in BUTTON_NAMES.iter().enumerate() {
+                config.set(&section, name, Value::String(key_name(self.buttons[player][button])));
+            }
+            for (button, name) in TURBO_NAMES.iter().enumerate() {
+                config.set(&section, name, Value::String(key_name(self.turbo[player][button])));
+            }
+        }
+        let section = vec![String::from("hotkeys")];
+        for (n, &(_, name, _)) in HOTKEYS.iter().enumerate() {
+            config.set(&section, name, Value::String(key_name(self.hotkeys[n])));
+        }
+    }
+
+    pub fn hotkey(&self, key: Keycode) -> Option<Hotkey> {
+        HOTKEYS.iter()
+            .zip(self.hotkeys.iter())
+            .find(|&(_, &bound)| bound == Some(key))
+            .map(|(&(hotkey, _, _), _)| hotkey)
+    }
+
+    // for hotkeys that do something while held
+    pub fn held(&self, hotkey: Hotkey, keys: &[Keycode]) -> bool {
+        HOTKEYS.iter()
+            .zip(self.hotkeys.iter())
+            .any(|(&(bound_hotkey, _, _), bound)| {
+                bound_hotkey == hotkey && bound.is_some_and(|key| keys.contains(&key))
+            })
+    }
+
+    // button bitmask for Joy::set_buttons
+    pub fn buttons(&self, player: usize, keys: &[Keycode]) -> u8 {
+        let mut buttons = 0;
+        for (bit, bound) in self.buttons[player].iter().enumerate() {
+            if bound.is_some_and(|key| keys.contains(&key)) {
+                buttons |= 1 << bit;
+            }
+        }
+        buttons
+    }
+
+    // held turbo buttons, for Joy::set_buttons to fire
+    pub fn turbo(&self, player: usize, keys: &[Keycode]) -> u8 {
+        let mut turbo = 0;
+        for (bit, bound) in self.turbo[player].iter().enumerate() {
+            if bound.is_some_and(|key| keys.contains(&key)) {
+                turbo |= 1 << bit;
+            }
+        }
+        turbo
+    }
+
+    // a key only does one thing
+    fn unbind(&mut self, key: Keycode) {
+        for player in self.buttons.iter_mut() {
+            for bound in player.iter_mut() {
+                if *bound == Some(key) {
+                    *bound = None;
+                }
+            }
+        }
+        for player in self.turbo.iter_mut() {
+            for bound in player.iter_mut() {
+                if *bound == Some(key) {
+                    *bound = None;
+                }
+            }
+        }
+        for bound in self.hotkeys.iter_mut() {
+            if *bound == Some(key) {
+                *bound = None;
+            }
+        }
+    }
+}
+
+// Asks for a key for every button of every player and then every hotkey.
+// Escape keeps the current binding.
+pub struct Rebinder {
+    step: usize,
+}
+
+impl Rebinder {
+    pub fn new() -> Rebinder {
+        Rebinder { step: 0 }
+    }
+
+    pub fn prompt(&self, bindings: &Bindings) -> String {
+        let (name, current) = if self.step < PLAYERS * PLAYER_KEYS {
+            let (player, button) = (self.step / PLAYER_KEYS, self.step % PLAYER_KEYS);
+            if button < 8 {
+                (format!("player {} {}", player + 1, BUTTON_NAMES[button]),
+                 bindings.buttons[player][button])
+            } else {
+                (format!("player {} {}", player + 1, TURBO_NAMES[button - 8].replace('_', " ")),
+                 bindings.turbo[player][button - 8])
+            }
+        } else {
+            let n = self.step - PLAYERS * PLAYER_KEYS;
+            (HOTKEYS[n].1.replace('_', " "), bindings.hotkeys[n])
+        };
+        format!("Press a key for {} (Escape keeps {})", name, display_name(current))
+    }
+
+    // true once everything has been asked for
+    pub fn press(&mut self, bindings: &mut Bindings, key: Keycode) -> bool {
+        if key != Keycode::Escape {
+            bindings.unbind(key);
+            let (player, button) = (self.step / PLAYER_KEYS, self.step % PLAYER_KEYS);
+            if self.step >= PLAYERS * PLAYER_KEYS {
+                bindings.hotkeys[self.step - PLAYERS * PLAYER_KEYS] = Some(key);
+            } else if button < 8 {
+                bindings.buttons[player][button] = Some(key);
+            } else {
+                bindings.turbo[player][button - 8] = Some(key);
+            }
+        }
+        self.step += 1;
+        self.step == PLAYERS * PLAYER_KEYS + HOTKEYS.len()
+    }
+}
+
+fn player_section(player: usize) -> String {
+    format!("player{}", player + 1)
+}
+
+fn parse_key(name: &str) -> Option<Keycode> {
+    if name.is_empty() {
+        return None;
+    }
+    let key = Keycode::from_name(name);
+    if key.is_none() {
+        println!("Unknown key {:?}", name);
+    }
+    key
+}
+
+fn key_name(key: Option<Keycode>) -> String {
+    key.map_or(String::new(), |key| key.name())
+}
+
+fn display_name(key: Option<Keycode>) -> String {
+    key.map_or(String::from("nothing"), |key| key.name())
+}
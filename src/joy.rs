@@ -1,73 +1,136 @@
-use sdl2::keyboard::Keycode;
+// buttons go A, B, Select, Start, Up, Down, Left, Right from bit 0, the
+// order the pad reports them
+const UP: u8 = 1 << 4;
+const DOWN: u8 = 1 << 5;
+const LEFT: u8 = 1 << 6;
+const RIGHT: u8 = 1 << 7;
+// turbo only applies to A and B
+const TURBO_MASK: u8 = 0x03;
 
-pub struct Joy {
-    joy1: u8,
-    _joy2: u8,
+// the upper bits of $4016/$4017 aren't driven, they're left over from
+// the $40 of the address
+pub const OPEN_BUS: u8 = 0x40;
 
-    joy1_read: u8,
-    joy2_read: u8,
+// Standard controllers are a 4021 shift register each. While the strobe
+// bit is set they keep reloading the buttons, after it's cleared every read
+// shifts one out, A first, and once all 8 are out they read as 1.
+pub struct Joy {
+    buttons: [u8; 2],
+    shift: [u8; 2],
+    strobe: bool,
+    // drop up+down and left+right, which a real pad can't press and
+    // which breaks some games
+    pub block_opposite: bool,
+    // frames turbo buttons spend pressed, then released
+    pub turbo_rate: usize,
 }
 
 impl Joy {
     pub fn new() -> Joy {
         Joy {
-            joy1: 0,
-            _joy2: 0,
-
-            joy1_read: 0,
-            joy2_read: 0,
+            buttons: [0; 2],
+            shift: [0; 2],
+            strobe: false,
+            block_opposite: false,
+            turbo_rate: 2,
         }
     }
 
-    pub fn set_keys(&mut self, keys: Vec<Keycode>) {
-        self.joy1 = 0;
-        for key in keys {
-            match key {
-                Keycode::LCtrl => {
-                    self.joy1 |= 1 << 0;
-                }
-                Keycode::LShift => {
-                    self.joy1 |= 1 << 1;
-                }
-                Keycode::Space => {
-                    self.joy1 |= 1 << 2;
-                }
-                Keycode::Return => {
-                    self.joy1 |= 1 << 3;
-                }
-                Keycode::Up => {
-                    self.joy1 |= 1 << 4;
-                }
-                Keycode::Down => {
-                    self.joy1 |= 1 << 5;
-                }
-                Keycode::Left => {
-                    self.joy1 |= 1 << 6;
-                }
-                Keycode::Right => {
-                    self.joy1 |= 1 << 7;
-                }
-                _ => ()// panic!("Unkown key {:?}", key),
+    // Turbo buttons are on for turbo_rate frames and off for as many,
+    // counted from the emulated frame so it doesn't drift with the host.
+    // frame has to keep counting while rendering is off too.
+    pub fn set_buttons(&mut self, player: usize, buttons: u8, turbo: u8, frame: usize) {
+        let mut buttons = buttons;
+        if (frame / self.turbo_rate.max(1)) & 1 == 0 {
+            buttons |= turbo & TURBO_MASK;
+        }
+        if self.block_opposite {
+            if buttons & (UP | DOWN) == UP | DOWN {
+                buttons &= !(UP | DOWN);
+            }
+            if buttons & (LEFT | RIGHT) == LEFT | RIGHT {
+                buttons &= !(LEFT | RIGHT);
             }
         }
+        self.buttons[player] = buttons;
+        if self.strobe {
+            self.shift[player] = buttons;
+        }
     }
 
+    // $4016, bit 0 goes to both controllers
     pub fn strobe_joy(&mut self, value: u8) {
-        if value == 0 {
-            self.joy1_read = 0;
-            // self.joy1 = 0;
-            self.joy2_read = 0;
-        } else {
-            // self.joy1 = 1;
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    // $4016 for player 1, $4017 for player 2
+    pub fn read(&mut self, player: usize) -> u8 {
+        if self.strobe {
+            self.shift[player] = self.buttons[player];
+        }
+        let bit = self.shift[player] & 1;
+        if !self.strobe {
+            self.shift[player] = (self.shift[player] >> 1) | 0x80;
+        }
+        bit | OPEN_BUS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_byte(joy: &mut Joy, player: usize) -> u8 {
+        let mut value = 0;
+        for bit in 0..8 {
+            value |= (joy.read(player) & 1) << bit;
         }
+        value
     }
 
-    pub fn read_joy1(&mut self) -> u8 {
-        let ret = (self.joy1 & (1 << self.joy1_read)) >> self.joy1_read;
-        // println!("joy1 {:#b} ret {} at {}", self.joy1, ret, self.joy1_read);
-        // self.joy1 &= !(1 << self.joy1_read);
-        self.joy1_read = (self.joy1_read + 1) % 8;
-        // if self.joy1_read > 8
-        ret
+    #[test]
+    fn shifts_out_then_reads_1() {
+        let mut joy = Joy::new();
+        joy.set_buttons(0, 0b1010_0101, 0, 0);
+        joy.strobe_joy(1);
+        joy.strobe_joy(0);
+        assert_eq!(read_byte(&mut joy, 0), 0b1010_0101);
+        for _ in 0..16 {
+            assert_eq!(joy.read(0), 1 | OPEN_BUS);
+        }
+        // player 2 shifts on its own
+        assert_eq!(read_byte(&mut joy, 1), 0);
+    }
+
+    #[test]
+    fn strobe_held_keeps_reloading() {
+        let mut joy = Joy::new();
+        joy.set_buttons(0, 0b0000_0010, 0, 0);
+        joy.strobe_joy(1);
+        for _ in 0..4 {
+            assert_eq!(joy.read(0), OPEN_BUS);
+        }
+        joy.set_buttons(0, 0b0000_0001, 0, 0);
+        assert_eq!(joy.read(0), 1 | OPEN_BUS);
+        assert_eq!(joy.read(0), 1 | OPEN_BUS);
+        joy.strobe_joy(0);
+        assert_eq!(read_byte(&mut joy, 0), 0b0000_0001);
+    }
+
+    #[test]
+    fn block_opposite() {
+        let mut joy = Joy::new();
+        joy.set_buttons(0, UP | DOWN | LEFT | 1, 0, 0);
+        joy.strobe_joy(1);
+        assert_eq!(joy.buttons[0], UP | DOWN | LEFT | 1);
+
+        joy.block_opposite = true;
+        joy.set_buttons(0, UP | DOWN | LEFT | 1, 0, 0);
+        assert_eq!(joy.buttons[0], LEFT | 1);
+        joy.set_buttons(0, UP | LEFT | RIGHT, 0, 0);
+        assert_eq!(joy.buttons[0], UP);
     }
 }
@@ -1,10 +1,13 @@
 extern crate sdl2;
 extern crate time;
 extern crate ringbuf;
+extern crate toml;
 
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::keyboard::Keycode;
-use sdl2::event::Event;
+use sdl2::mouse::MouseButton;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::rect::Rect;
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
 use std::sync::{Arc, Mutex};
 use ringbuf::{RingBuffer, Consumer};
@@ -13,6 +16,7 @@ use ringbuf::{RingBuffer, Consumer};
 // use time;
 
 use std::env;
+use std::path::Path;
 use std::fmt;
 
 mod cart;
@@ -20,12 +24,34 @@ mod mem_map;
 mod cpu;
 mod apu;
 mod ppu;
+mod palette;
+mod ntsc;
+mod filters;
+mod hqx;
+mod xbrz;
+mod config;
+mod display;
+mod screenshot;
+mod viewer;
+mod events;
+mod blip;
+mod audio;
+mod mixer;
+mod wav;
+mod recorder;
+mod vgm;
+mod expansion;
+mod vrc6;
+mod input;
+mod gamepad;
 mod joy;
+mod zapper;
 mod opcodes;
 
 use opcodes::AddressMode;
 
 use mem_map::*;
+use input::Hotkey;
 // use cpu::RunCondition;
 
 const PPU_MULTIPLIER:isize = 3;
@@ -36,10 +62,16 @@ pub struct Bus {
     apu: apu::APU,
     ppu: ppu::PPU,
     joy: joy::Joy,
+    // in place of player 2's controller
+    zapper: Option<zapper::Zapper>,
 }
 
 pub struct ApuOut {
     rb: Consumer<f32>,
+    stats: Arc<audio::AudioStats>,
+    channels: usize,
+    // last sample of each channel, repeated when running dry
+    last: [f32; 2],
 //    phase: Arc<Mutex<Vec<f32>>>,
 //    rx: Receiver<f32>,
 }
@@ -47,54 +79,305 @@ pub struct ApuOut {
 impl AudioCallback for ApuOut {
     type Channel = f32;
     fn callback(&mut self, out: &mut [f32]) {
-        // buffer prevents pops when underflowing the actual output
-        // it will make the sound very slgihtly wrong on underflow
+        // repeating the last sample when underflowing the actual output
+        // will make the sound very slgihtly wrong on underflow
         // but it sounds better than a pop
-        let mut sample: f32 = 0.0;
-        let mut buffer: f32;
-        for x in out.iter_mut() {
-            buffer = sample;
-            sample = if self.rb.is_empty() {
-                buffer 
+        let mut ran_dry = false;
+        for (n, x) in out.iter_mut().enumerate() {
+            let channel = n % self.channels;
+            if let Some(sample) = self.rb.pop() {
+                self.last[channel] = sample;
             } else {
-                self.rb.pop().unwrap()
-            };
+                ran_dry = true;
+            }
    //         sample = self.phase.lock().unwrap().pop().unwrap_or(-1.0);
    //         if sample == -1.0 {sample = buffer};
-            *x = sample;
+            *x = self.last[channel];
 
         //    *x = self.rx.try_recv().unwrap_or(0.0);
         }
+        if ran_dry {
+            self.stats.underrun();
+        }
     }
 }
 
-fn main() {
-    let rompath = env::args().nth(1).unwrap_or(String::from("smb.nes"));
+struct Options {
+    rompath: String,
+    no_sprite_limit: bool,
+    hidden_sprites: u64,
+    palette: String,
+    ntsc: palette::NtscParams,
+    filter: filters::Filter,
+    ntsc_settings: ntsc::NtscSettings,
+    config_path: String,
+    overscan: Option<display::Overscan>,
+    aspect: Option<display::Aspect>,
+    integer_scale: bool,
+    latency: Option<usize>,
+    sample_rate: Option<i32>,
+    audio_quality: Option<blip::Quality>,
+    audio_filter: Option<audio::FilterProfile>,
+    stereo: bool,
+    muted: Vec<usize>,
+    record: Option<String>,
+    record_stems: bool,
+    headless: bool,
+    frames: usize,
+    vgm: Option<String>,
+    block_opposite: bool,
+    zapper: bool,
+}
 
-    let sdl = sdl2::init().unwrap();
-    let video = sdl.video().unwrap();
-    let window = video.window("OxideNES", 256 * 2, 240 * 2)
-        .position_centered()
-        .opengl()
-        .build()
-        .unwrap();
+fn parse_args() -> Options {
+    let mut options = Options {
+        rompath: String::from("smb.nes"),
+        no_sprite_limit: false,
+        hidden_sprites: 0,
+        palette: String::from("default"),
+        ntsc: palette::NtscParams::new(),
+        filter: filters::Filter::Nearest(1),
+        ntsc_settings: ntsc::NtscSettings::new(),
+        config_path: String::from("oxidenes.toml"),
+        overscan: None,
+        aspect: None,
+        integer_scale: false,
+        latency: None,
+        sample_rate: None,
+        audio_quality: None,
+        audio_filter: None,
+        stereo: false,
+        muted: Vec::new(),
+        record: None,
+        record_stems: false,
+        headless: false,
+        frames: 3600,
+        vgm: None,
+        block_opposite: false,
+        zapper: false,
+    };
 
-    let mut renderer = window.into_canvas().build().unwrap();
-    let t_c = renderer.texture_creator();
-    let mut texture = t_c.create_texture_streaming(PixelFormatEnum::RGB24,
-                                                        256,
-                                                        240).unwrap();
-    let mut events = sdl.event_pump().unwrap();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-sprite-limit" => options.no_sprite_limit = true,
+            // comma separated OAM entries, ie --hide-sprites 0,12,63
+            "--hide-sprites" => {
+                let list = args.next().unwrap_or_default();
+                for entry in list.split(',') {
+                    match entry.trim().parse::<u64>() {
+                        Ok(n) if n < 64 => options.hidden_sprites |= 1 << n,
+                        _ => println!("Ignoring invalid sprite number {:?}", entry),
+                    }
+                }
+            }
+            "--palette" => options.palette = args.next().unwrap_or(options.palette),
+            "--hue" => options.ntsc.hue = parse_f32(args.next(), options.ntsc.hue),
+            "--saturation" => {
+                options.ntsc.saturation = parse_f32(args.next(), options.ntsc.saturation)
+            }
+            "--contrast" => options.ntsc.contrast = parse_f32(args.next(), options.ntsc.contrast),
+            "--brightness" => {
+                options.ntsc.brightness = parse_f32(args.next(), options.ntsc.brightness)
+            }
+            "--gamma" => options.ntsc.gamma = parse_f32(args.next(), options.ntsc.gamma),
+            "--ntsc" => options.filter = filters::Filter::Ntsc,
+            "--filter" => {
+                let name = args.next().unwrap_or_default();
+                match filters::Filter::parse(&name) {
+                    Some(filter) => options.filter = filter,
+                    None => println!("Unknown filter {:?}", name),
+                }
+            }
+            "--ntsc-sharpness" => {
+                let sharpness = &mut options.ntsc_settings.sharpness;
+                *sharpness = parse_f32(args.next(), *sharpness);
+            }
+            "--ntsc-resolution" => {
+                let resolution = &mut options.ntsc_settings.resolution;
+                *resolution = parse_f32(args.next(), *resolution);
+            }
+            "--ntsc-merge-fields" => options.ntsc_settings.merge_fields = true,
+            "--ntsc-width" => {
+                let width = options.ntsc_settings.width as f32;
+                options.ntsc_settings.width = parse_f32(args.next(), width) as usize;
+            }
+            "--config" => options.config_path = args.next().unwrap_or(options.config_path),
+            // top,bottom,left,right in NES pixels, ie --overscan 8,8,0,0
+            "--overscan" => {
+                let list = args.next().unwrap_or_default();
+                let sides: Vec<usize> = list.split(',')
+                    .filter_map(|side| side.trim().parse().ok())
+                    .filter(|&side| side < 64)
+                    .collect();
+                if sides.len() == 4 {
+                    options.overscan = Some(display::Overscan {
+                        top: sides[0],
+                        bottom: sides[1],
+                        left: sides[2],
+                        right: sides[3],
+                    });
+                } else {
+                    println!("Ignoring invalid overscan {:?}", list);
+                }
+            }
+            "--aspect" => {
+                let name = args.next().unwrap_or_default();
+                match display::Aspect::parse(&name) {
+                    Some(aspect) => options.aspect = Some(aspect),
+                    None => println!("Unknown aspect ratio {:?}", name),
+                }
+            }
+            "--integer-scale" => options.integer_scale = true,
+            "--sample-rate" => {
+                let rate = args.next().unwrap_or_default();
+                match rate.parse::<i32>() {
+                    Ok(rate) if (8000..=192000).contains(&rate) => options.sample_rate = Some(rate),
+                    _ => println!("Ignoring invalid sample rate {:?}", rate),
+                }
+            }
+            // milliseconds of audio buffered ahead
+            "--latency" => {
+                let latency = args.next().unwrap_or_default();
+                match latency.parse::<usize>() {
+                    Ok(latency) if (5..=1000).contains(&latency) => options.latency = Some(latency),
+                    _ => println!("Ignoring invalid latency {:?}", latency),
+                }
+            }
+            "--audio-filter" => {
+                let name = args.next().unwrap_or_default();
+                match audio::FilterProfile::parse(&name) {
+                    Some(profile) => options.audio_filter = Some(profile),
+                    None => println!("Unknown audio filter {:?}", name),
+                }
+            }
+            "--audio-quality" => {
+                let name = args.next().unwrap_or_default();
+                match blip::Quality::parse(&name) {
+                    Some(quality) => options.audio_quality = Some(quality),
+                    None => println!("Unknown audio quality {:?}", name),
+                }
+            }
+            "--stereo" => options.stereo = true,
+            // comma separated channel names, ie --mute triangle,noise
+            "--mute" => {
+                let list = args.next().unwrap_or_default();
+                for name in list.split(',') {
+                    match mixer::Mixer::channel_index(name.trim()) {
+                        Some(channel) => options.muted.push(channel),
+                        None => println!("Unknown channel {:?}", name),
+                    }
+                }
+            }
+            "--record" => options.record = args.next(),
+            "--record-stems" => options.record_stems = true,
+            "--headless" => options.headless = true,
+            "--vgm" => options.vgm = args.next(),
+            "--block-opposite" => options.block_opposite = true,
+            "--zapper" => options.zapper = true,
+            // how long to run headless, 60 frames a second
+            "--frames" => {
+                let frames = args.next().unwrap_or_default();
+                match frames.parse::<usize>() {
+                    Ok(frames) => options.frames = frames,
+                    _ => println!("Ignoring invalid frame count {:?}", frames),
+                }
+            }
+            _ => options.rompath = arg,
+        }
+    }
+    options
+}
 
-    let audio_subsystem = sdl.audio().unwrap();
+fn parse_f32(arg: Option<String>, default: f32) -> f32 {
+    match arg {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            println!("Ignoring invalid number {:?}", value);
+            default
+        }),
+        None => default,
+    }
+}
+
+fn main() {
+    let options = parse_args();
+    let rompath = options.rompath.clone();
+
+    // a missing config file just means the defaults
+    let mut config = if Path::new(&options.config_path).exists() {
+        config::Config::load(&options.config_path).unwrap_or_else(|e| {
+            println!("Couldn't load config {}", e);
+            config::Config::new()
+        })
+    } else {
+        // the first run writes out the default bindings to edit
+        let mut config = config::Config::new();
+        input::Bindings::new().save_to(&mut config);
+        match config.save(&options.config_path) {
+            Ok(()) => println!("Wrote default config {}", options.config_path),
+            Err(e) => println!("Couldn't write config {}", e),
+        }
+        config
+    };
+    let mut bindings = input::Bindings::from_config(&config);
+    let rom_name = Path::new(&rompath).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or(rompath.clone());
+    let mut display = display::DisplaySettings::from_config(&config, &rom_name);
+    if let Some(overscan) = options.overscan {
+        display.overscan = overscan;
+    }
+    if let Some(aspect) = options.aspect {
+        display.aspect = aspect;
+    }
+    display.integer_scale |= options.integer_scale;
+
+    // [audio] sample_rate and quality, the command line wins
+    let sample_rate = options.sample_rate.unwrap_or_else(|| {
+        match config.get_int(&["audio"], "sample_rate") {
+            Some(rate) if (8000..=192000).contains(&rate) => rate as i32,
+            _ => 44100,
+        }
+    });
+    let audio_quality = options.audio_quality.unwrap_or_else(|| {
+        config.get_str(&["audio"], "quality")
+            .and_then(blip::Quality::parse)
+            .unwrap_or(blip::Quality::Medium)
+    });
+    let audio_filter = options.audio_filter.unwrap_or_else(|| {
+        config.get_str(&["audio"], "filter")
+            .and_then(audio::FilterProfile::parse)
+            .unwrap_or(audio::FilterProfile::Nes)
+    });
+    let latency_ms = options.latency.unwrap_or_else(|| {
+        match config.get_int(&["audio"], "latency") {
+            Some(latency) if (5..=1000).contains(&latency) => latency as usize,
+            _ => 40,
+        }
+    });
+    let latency = audio::latency_samples(latency_ms, sample_rate);
+    let mut mixer = mixer::Mixer::from_config(&config);
+    mixer.stereo |= options.stereo;
+    for &channel in &options.muted {
+        mixer.channels[channel].enabled = false;
+    }
+    mixer.update();
+    let channels = mixer.output_channels();
+    // the device buffer has to be well under the latency to hit it
+    let mut device_samples = 256;
+    while device_samples * 4 <= latency && device_samples < 4096 {
+        device_samples *= 2;
+    }
     let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),  // mono
-        samples: Some(441),       // default sample size
+        freq: Some(sample_rate),
+        channels: Some(channels as u8),
+        samples: Some(device_samples as u16),
     };
 
-    let rb = RingBuffer::<f32>::new(2048);
+    // twice the latency, the emulator keeps it half full
+    let rb = RingBuffer::<f32>::new(latency * 2 * channels);
     let (mut prod, mut cons) = rb.split();
+    let audio_stats = Arc::new(audio::AudioStats::new());
     // let (tx, rx) = channel();
 
 
@@ -103,11 +386,32 @@ fn main() {
     println!("{:#?}", cart);
     let chr_rom = cart::ChrRom::new(&rompath);
     // let apu = apu::APU::new(tx);
-    let apu = apu::APU::new(prod);
-
-
-    let ppu = ppu::PPU::new(chr_rom);
-    let joy = joy::Joy::new();
+    let mut apu = apu::APU::new(prod,
+                                sample_rate as f64,
+                                audio_quality,
+                                audio_filter,
+                                mixer,
+                                audio_stats.clone());
+    apu.set_expansion(cart.expansion_audio());
+
+
+    let mut ppu = ppu::PPU::new(chr_rom);
+    ppu.no_sprite_limit = options.no_sprite_limit;
+    ppu.hidden_sprites = options.hidden_sprites;
+    let mut joy = joy::Joy::new();
+    // [input] block_opposite, or --block-opposite
+    joy.block_opposite = options.block_opposite ||
+        config.get_bool(&["input"], "block_opposite").unwrap_or(false);
+    // [input] turbo_rate, frames on and then off
+    if let Some(rate) = config.get_int(&["input"], "turbo_rate") {
+        joy.turbo_rate = rate.max(1) as usize;
+    }
+    // [input] zapper, or --zapper
+    let zapper = if options.zapper || config.get_bool(&["input"], "zapper").unwrap_or(false) {
+        Some(zapper::Zapper::new())
+    } else {
+        None
+    };
 
     let cpubus = Bus {
         ram: vec![0; RAM_LEN as usize].into_boxed_slice(),
@@ -115,6 +419,7 @@ fn main() {
         apu: apu,
         ppu: ppu,
         joy: joy,
+        zapper: zapper,
     };
 
     let pc = cpubus.cart.read_cart_u16(RESET_VECTOR_LOC);
@@ -124,13 +429,75 @@ fn main() {
     // let f = Rc::new(&cpu);
     // cpu.bus.apu.setup_read_u8(f);
 
+    if let Some(ref path) = options.record {
+        match cpu.bus.apu.start_recording(path, options.record_stems) {
+            Ok(()) => println!("Recording to {}", path),
+            Err(e) => println!("Couldn't record to {}: {}", path, e),
+        }
+    }
+    if let Some(ref path) = options.vgm {
+        match cpu.start_vgm(path) {
+            Ok(()) => println!("Logging VGM to {}", path),
+            Err(e) => println!("Couldn't log VGM to {}: {}", path, e),
+        }
+    }
+    if options.headless {
+        run_headless(&mut cpu, &mut cons, options.frames);
+        return;
+    }
+
+    let sdl = sdl2::init().unwrap();
+    let video = sdl.video().unwrap();
+    let (window_w, window_h) = display.window_size(2);
+    let window = video.window("OxideNES", window_w, window_h)
+        .position_centered()
+        .resizable()
+        .opengl()
+        .build()
+        .unwrap();
+
+    let main_window_id = window.id();
+    let mut renderer = window.into_canvas().build().unwrap();
+    let t_c = renderer.texture_creator();
+    let mut texture = t_c.create_texture_streaming(PixelFormatEnum::RGB24,
+                                                        256,
+                                                        240).unwrap();
+
+    // the debug viewer window always exists so its texture can live as
+    // long as the main one, F6 just shows and hides it
+    let debug_window = video.window("OxideNES debug", viewer::MAX_WIDTH as u32, viewer::MAX_HEIGHT as u32)
+        .hidden()
+        .build()
+        .unwrap();
+    let debug_window_id = debug_window.id();
+    let mut debug_renderer = debug_window.into_canvas().build().unwrap();
+    let debug_t_c = debug_renderer.texture_creator();
+    let mut debug_texture = debug_t_c.create_texture_streaming(PixelFormatEnum::RGB24,
+                                                               viewer::MAX_WIDTH as u32,
+                                                               viewer::MAX_HEIGHT as u32).unwrap();
+    let mut viewer = viewer::Viewer::new();
+    let mut debug_shown = false;
+
+    let ntsc_filter = ntsc::NtscFilter::new(&options.ntsc_settings, &options.ntsc);
+    let mut filter_chain = filters::FilterChain::new(options.filter, ntsc_filter);
+    let mut events = sdl.event_pump().unwrap();
+    let mut gamepads = gamepad::Gamepads::new(sdl.game_controller().unwrap(),
+                                              sdl.joystick().unwrap(),
+                                              &config);
+
+    let audio_subsystem = sdl.audio().unwrap();
+
     let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
         // Show obtained AudioSpec
         println!("{:?}", spec);
+        cpu.bus.apu.set_sample_rate(spec.freq as f64);
 
         // initialize the audio callback
         ApuOut {
             rb: cons,
+            stats: audio_stats.clone(),
+            channels,
+            last: [0.0; 2],
 //            phase: cpu.bus.apu.output.clone(),
         //    rx: rx,
         }
@@ -140,81 +507,372 @@ fn main() {
     // TODO: re-add specific run conditions for debugging
 //    let mut nmi = false;
 //    let mut irq = false;
-    let mut framestart = time::precise_time_ns();
+    let mut palette = palette::Palette::load(&options.palette, &options.ntsc).unwrap_or_else(|e| {
+        println!("Couldn't load palette {}, using the default", e);
+        palette::Palette::new()
+    });
+    let mut builtin_palette = 0;
+    let mut frame: Vec<u32> = Vec::new();
+    let mut frame_size = (256, 240);
+    let mut paused = false;
+    let mut fast_forward = false;
+    let mut rebinding: Option<input::Rebinder> = None;
     'main: loop {
-
-        let (op, instr) = cpu.read_instruction();
-        if op == 0 {
-            println!("BRK, quitting!");
+        if !step(&mut cpu) {
             break;
         }
 
-        // TODO: Move this to a specific debug output
-        if false {
-            cpu_debug(&op, &instr, &cpu);
-        }
-
-        cpu.cycle += instr.ticks as isize * PPU_MULTIPLIER;
-        let (nmi, mut irq) = cpu.bus.ppu.tick(instr.ticks as isize * PPU_MULTIPLIER);
-
-        if cpu.bus.ppu.extra_cycle {
-            cpu.cycle += 1;
-            cpu.bus.ppu.extra_cycle = false;
-        }
-
-        if cpu.cycle >= 341 {
-            cpu.cycle %= 341;
-
-            if cpu.bus.ppu.scanline == 240 {
-                render_frame(&cpu.bus.ppu.screen, &mut renderer, &mut texture);
-
-                // Frame limiter.
-                let mut frametime = time::precise_time_ns() - framestart;
-                // println!("Frame took {}", frametime);
-                if frametime < 16_666_667 {
-                    frametime = 16_666_667 - frametime;
-                    std::thread::sleep(std::time::Duration::new(0, frametime as u32));
-                }
-                framestart = time::precise_time_ns();
+        if cpu.bus.ppu.frame_ready {
+            cpu.bus.ppu.frame_ready = false;
+            let scale = filter_chain.window_scale();
+            filter_chain.process(&cpu.bus.ppu.screen, &palette, cpu.bus.ppu.framecount);
+            let size = display.crop(&filter_chain.output,
+                                    filter_chain.width,
+                                    filter_chain.height,
+                                    &mut frame);
+            if size != frame_size {
+                texture = t_c.create_texture_streaming(PixelFormatEnum::RGB24,
+                                                       size.0 as u32,
+                                                       size.1 as u32).unwrap();
+                frame_size = size;
+            }
+            if scale != filter_chain.window_scale() {
+                let (w, h) = display.window_size(filter_chain.window_scale());
+                renderer.window_mut().set_size(w, h).unwrap();
+            }
+            let (window_w, window_h) = renderer.output_size().unwrap();
+            let (x, y, w, h) = display.dest_rect(window_w, window_h);
+            render_frame(&frame,
+                         frame_size.0,
+                         Rect::new(x, y, w, h),
+                         &mut renderer,
+                         &mut texture);
+
+            cpu.bus.ppu.events.enabled = debug_shown && viewer.view == viewer::View::Events;
+            if debug_shown {
+                viewer.render(&cpu.bus.ppu, &palette);
+                upload_pixels(&viewer.output, viewer.width, &mut debug_texture);
+                let src = Rect::new(0, 0, viewer.width as u32, viewer.height as u32);
+                debug_renderer.clear();
+                debug_renderer.copy(&debug_texture, Some(src), None).unwrap();
+                debug_renderer.present();
+            }
 
+            // Audio drives the pacing, the callback drains the buffer in
+            // real time so wait for it to get back down to the latency.
+            // The timeout keeps things going if the device stalls.
+            let waitstart = time::precise_time_ns();
+            while !fast_forward && cpu.bus.apu.buffered() > latency &&
+                time::precise_time_ns() - waitstart < 50_000_000
+            {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
 
+            // while paused this just keeps handling events
+            loop {
                 for event in events.poll_iter() {
-                    match event {
-                        Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                            break 'main
+                    let key = match event {
+                        Event::Quit {..} => break 'main,
+                        Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                            if window_id != debug_window_id {
+                                break 'main
+                            }
+                            debug_shown = false;
+                            debug_renderer.window_mut().hide();
+                            continue;
+                        }
+                        Event::MouseMotion { window_id, x, y, .. } if window_id == debug_window_id => {
+                            let scale = viewer.scale() as i32;
+                            let title = match viewer.describe(&cpu.bus.ppu,
+                                                              (x / scale) as usize,
+                                                              (y / scale) as usize) {
+                                Some(info) => format!("OxideNES {} - {}", viewer.view.name(), info),
+                                None => format!("OxideNES {}", viewer.view.name()),
+                            };
+                            debug_renderer.window_mut().set_title(&title).unwrap();
+                            continue;
+                        }
+                        Event::MouseMotion { window_id, x, y, .. } if window_id == main_window_id => {
+                            if let Some(ref mut zapper) = cpu.bus.zapper {
+                                // the mouse is in window points, which on a
+                                // HiDPI display aren't the drawable's pixels
+                                let (window_w, window_h) = renderer.window().size();
+                                let (output_w, output_h) = renderer.output_size().unwrap();
+                                let x = (x as i64 * output_w as i64 / window_w.max(1) as i64) as i32;
+                                let y = (y as i64 * output_h as i64 / window_h.max(1) as i64) as i32;
+                                zapper.aim = display.screen_position(output_w, output_h, x, y);
+                            }
+                            continue;
+                        }
+                        Event::MouseButtonDown { window_id, mouse_btn: MouseButton::Left, .. }
+                            if window_id == main_window_id => {
+                            if let Some(ref mut zapper) = cpu.bus.zapper {
+                                zapper.trigger = true;
+                            }
+                            continue;
                         }
-                        _ => ()
+                        Event::MouseButtonUp { window_id, mouse_btn: MouseButton::Left, .. }
+                            if window_id == main_window_id => {
+                            if let Some(ref mut zapper) = cpu.bus.zapper {
+                                zapper.trigger = false;
+                            }
+                            continue;
+                        }
+                        Event::ControllerDeviceAdded { which, .. } => {
+                            gamepads.add(which, &config);
+                            continue;
+                        }
+                        Event::ControllerDeviceRemoved { which, .. } => {
+                            gamepads.remove(which);
+                            continue;
+                        }
+                        Event::KeyDown { keycode: Some(key), repeat: false, .. } => key,
+                        _ => continue,
+                    };
+
+                    if let Some(mut rebinder) = rebinding.take() {
+                        if rebinder.press(&mut bindings, key) {
+                            bindings.save_to(&mut config);
+                            match config.save(&options.config_path) {
+                                Ok(()) => println!("Saved bindings to {}", options.config_path),
+                                Err(e) => println!("Couldn't save bindings {}", e),
+                            }
+                        } else {
+                            println!("{}", rebinder.prompt(&bindings));
+                            rebinding = Some(rebinder);
+                        }
+                        continue;
                     }
-                }
 
-                let keys: Vec<Keycode> = events.
-                                keyboard_state().
-                                pressed_scancodes().
-                                filter_map(Keycode::from_scancode).
-                                collect();
+                    match bindings.hotkey(key) {
+                        Some(Hotkey::Quit) => break 'main,
+                        Some(Hotkey::ToggleBackground) => {
+                            cpu.bus.ppu.hide_bg = !cpu.bus.ppu.hide_bg;
+                        }
+                        Some(Hotkey::ToggleSprites) => {
+                            cpu.bus.ppu.hide_sprites = !cpu.bus.ppu.hide_sprites;
+                        }
+                        Some(Hotkey::ToggleSpriteLimit) => {
+                            cpu.bus.ppu.no_sprite_limit = !cpu.bus.ppu.no_sprite_limit;
+                            println!("Sprite limit {}", if cpu.bus.ppu.no_sprite_limit {
+                                "off"
+                            } else {
+                                "on"
+                            });
+                        }
+                        Some(Hotkey::NextPalette) => {
+                            builtin_palette = (builtin_palette + 1) % palette::BUILTIN_PALETTES.len();
+                            let name = palette::BUILTIN_PALETTES[builtin_palette];
+                            println!("Palette: {}", name);
+                            palette = palette::Palette::builtin(name, &options.ntsc).unwrap();
+                        }
+                        Some(Hotkey::NextFilter) => {
+                            filter_chain.filter = filter_chain.filter.next();
+                            println!("Filter: {}", filter_chain.filter.name());
+                        }
+                        Some(Hotkey::DebugViewer) => {
+                            debug_shown = !debug_shown;
+                            if debug_shown {
+                                resize_viewer(&viewer, &mut debug_renderer);
+                                debug_renderer.window_mut().show();
+                            } else {
+                                debug_renderer.window_mut().hide();
+                            }
+                        }
+                        Some(Hotkey::NextDebugView) => {
+                            viewer.view = viewer.view.next();
+                            viewer.render(&cpu.bus.ppu, &palette);
+                            resize_viewer(&viewer, &mut debug_renderer);
+                        }
+                        Some(Hotkey::NextPatternPalette) => {
+                            viewer.palette_index = (viewer.palette_index + 1) % 8;
+                            println!("Pattern table palette {}", viewer.palette_index);
+                        }
+                        Some(Hotkey::AudioStats) => {
+                            println!("Audio: {}/{} samples buffered, rate {:.4}, {} underruns, {} overruns",
+                                     cpu.bus.apu.buffered(),
+                                     latency * 2,
+                                     cpu.bus.apu.rate_ratio,
+                                     audio_stats.underruns(),
+                                     audio_stats.overruns());
+                        }
+                        Some(hotkey @ Hotkey::RecordAudio) | Some(hotkey @ Hotkey::RecordStems) => {
+                            if cpu.bus.apu.recording() {
+                                stop_recording(&mut cpu);
+                            } else {
+                                let path = screenshot::numbered_path(&rompath, "wav");
+                                let stems = hotkey == Hotkey::RecordStems;
+                                match cpu.bus.apu.start_recording(&path, stems) {
+                                    Ok(()) => println!("Recording to {}", path),
+                                    Err(e) => println!("Couldn't record to {}: {}", path, e),
+                                }
+                            }
+                        }
+                        Some(Hotkey::Screenshot) => {
+                            let (pixels, w, h) = display.aspect_corrected(&frame,
+                                                                          frame_size.0,
+                                                                          frame_size.1);
+                            let path = screenshot::next_path(&rompath);
+                            match screenshot::save_bmp(&path, &pixels, w, h) {
+                                Ok(()) => println!("Saved screenshot {}", path),
+                                Err(e) => println!("Couldn't save screenshot {}: {}", path, e),
+                            }
+                        }
+                        Some(Hotkey::Mute(channel)) => {
+                            let enabled = cpu.bus.apu.mixer.toggle(channel);
+                            println!("{} {}", mixer::CHANNEL_NAMES[channel], if enabled {
+                                "on"
+                            } else {
+                                "muted"
+                            });
+                        }
+                        Some(Hotkey::VgmLog) => {
+                            if cpu.bus.apu.vgm_logging() {
+                                stop_vgm(&mut cpu);
+                            } else {
+                                let path = screenshot::numbered_path(&rompath, "vgm");
+                                match cpu.start_vgm(&path) {
+                                    Ok(()) => println!("Logging VGM to {}", path),
+                                    Err(e) => println!("Couldn't log VGM to {}: {}", path, e),
+                                }
+                            }
+                        }
+                        Some(Hotkey::VgmLoop) => {
+                            if cpu.bus.apu.vgm_logging() {
+                                cpu.bus.apu.set_vgm_loop();
+                                println!("VGM loop point set");
+                            }
+                        }
+                        Some(Hotkey::SaveState) | Some(Hotkey::LoadState) | Some(Hotkey::Rewind) => {
+                            println!("Save states and rewind aren't supported yet");
+                        }
+                        Some(Hotkey::Pause) => {
+                            paused = !paused;
+                            println!("{}", if paused { "Paused" } else { "Unpaused" });
+                        }
+                        Some(Hotkey::Rebind) => {
+                            let rebinder = input::Rebinder::new();
+                            println!("{}", rebinder.prompt(&bindings));
+                            rebinding = Some(rebinder);
+                        }
+                        // checked every frame below
+                        Some(Hotkey::FastForward) | None => {}
+                    }
+                }
+                if !paused {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(16));
+            }
 
-                cpu.bus.joy.set_keys(keys);
+            let keys: Vec<Keycode> = events.
+                            keyboard_state().
+                            pressed_scancodes().
+                            filter_map(Keycode::from_scancode).
+                            collect();
+
+            fast_forward = bindings.held(Hotkey::FastForward, &keys);
+            for player in 0..input::PLAYERS {
+                let buttons = bindings.buttons(player, &keys) | gamepads.buttons(player);
+                let turbo = bindings.turbo(player, &keys) | gamepads.turbo(player);
+                let frame = cpu.bus.ppu.elapsed_frames;
+                cpu.bus.joy.set_buttons(player, buttons, turbo, frame);
             }
         }
-        irq |= cpu.bus.apu.tick(instr.ticks as isize, &cpu.bus.cart);
+    }
+    stop_recording(&mut cpu);
+    stop_vgm(&mut cpu);
+}
+
+// one instruction along with the PPU and APU cycles that go with it,
+// false once the program hits BRK
+fn step(cpu: &mut cpu::CPU) -> bool {
+    let (op, instr) = cpu.read_instruction();
+    if op == 0 {
+        println!("BRK, quitting!");
+        return false;
+    }
+
+    // TODO: Move this to a specific debug output
+    if false {
+        cpu_debug(&op, &instr, cpu);
+    }
+
+    cpu.cycle += instr.ticks as isize * PPU_MULTIPLIER;
+    let (nmi, mut irq) = cpu.bus.ppu.tick(instr.ticks as isize * PPU_MULTIPLIER);
+
+    if cpu.bus.ppu.extra_cycle {
+        cpu.cycle += 1;
+        cpu.bus.ppu.extra_cycle = false;
+    }
+
+    if cpu.cycle >= 341 {
+        cpu.cycle %= 341;
+    }
+    irq |= cpu.tick_apu(instr.ticks as isize);
+    irq |= cpu.bus.ppu.chr.cpu_clock(instr.ticks as isize);
 //        irq |= cpu.bus.cart.irq_clock(instr.ticks as isize * PPU_MULTIPLIER, cpu.bus.ppu.scanline);
 
-        cpu.execute_op(&op, &instr);
+    cpu.execute_op(&op, &instr);
+    cpu.finish_dmc_dma();
+
+    // OAM/DMC DMA runs the PPU and APU too
+    let (dma_nmi, dma_irq) = cpu.take_pending_interrupts();
+    let nmi = nmi || dma_nmi;
+    irq |= dma_irq;
 
-        // TODO: IRQ from apu
+    // TODO: IRQ from apu
 
-        if nmi {
-            //    println!("NMI");
-            cpu.nmi();
-            cpu.bus.ppu.tick(7 * PPU_MULTIPLIER);
-            irq |= cpu.bus.apu.tick(7, &cpu.bus.cart);
+    if nmi {
+        //    println!("NMI");
+        cpu.nmi();
+        cpu.bus.ppu.tick(7 * PPU_MULTIPLIER);
+        irq |= cpu.bus.apu.tick(7);
+        irq |= cpu.bus.ppu.chr.cpu_clock(7);
 //            irq |= cpu.bus.cart.irq_clock(7 * PPU_MULTIPLIER, cpu.bus.ppu.scanline);
-        }
-        if irq {
+    }
+    if irq {
 //            println!("IRQ potentially generated");
-            cpu.irq();
+        cpu.irq();
+    }
+    true
+}
+
+// no window or audio device, just runs for a number of frames so the
+// recording can be made as fast as the emulator goes
+fn run_headless(cpu: &mut cpu::CPU, cons: &mut Consumer<f32>, frames: usize) {
+    let mut frame = 0;
+    while frame < frames {
+        if !step(cpu) {
+            break;
+        }
+        if cpu.bus.ppu.frame_ready {
+            cpu.bus.ppu.frame_ready = false;
+            frame += 1;
+            // nothing is playing the audio
+            while cons.pop().is_some() {}
         }
     }
+    println!("Ran {} frames", frame);
+    stop_recording(cpu);
+    stop_vgm(cpu);
+}
+
+fn stop_recording(cpu: &mut cpu::CPU) {
+    match cpu.bus.apu.stop_recording() {
+        Ok(paths) => for path in paths {
+            println!("Saved recording {}", path);
+        },
+        Err(e) => println!("Couldn't finish recording: {}", e),
+    }
+}
+
+fn stop_vgm(cpu: &mut cpu::CPU) {
+    match cpu.bus.apu.stop_vgm() {
+        Ok(Some(path)) => println!("Saved VGM log {}", path),
+        Ok(None) => {}
+        Err(e) => println!("Couldn't finish VGM log: {}", e),
+    }
 }
 
 
@@ -299,20 +957,43 @@ fn cpu_debug (op: &u8, instr: &opcodes::Instruction, cpu: &cpu::CPU) {
 }
 
 
-fn render_frame(screen: &[[u32; 256]; 240],
+fn resize_viewer(viewer: &viewer::Viewer,
+                 renderer: &mut sdl2::render::Canvas<sdl2::video::Window>)
+{
+    let scale = viewer.scale();
+    let (w, h) = ((viewer.width * scale) as u32, (viewer.height * scale) as u32);
+    let window = renderer.window_mut();
+    window.set_size(w, h).unwrap();
+    window.set_title(&format!("OxideNES {}", viewer.view.name())).unwrap();
+}
+
+// pixels is a width wide buffer of 0xRRGGBB values
+fn render_frame(pixels: &[u32],
+                width: usize,
+                dest: Rect,
                 renderer: &mut sdl2::render::Canvas<sdl2::video::Window>,
                 texture: &mut sdl2::render::Texture,
                 // events: &mut sdl2::EventPump,
                 )
 {
-    //println!("Screen 10,10 {:#X}", screen[10][10]);
+    upload_pixels(pixels, width, texture);
+
+    renderer.clear();
+    renderer.copy(&texture, None, Some(dest));
+    renderer.present();
+
+}
+
+// copies pixels into the top left of the texture
+fn upload_pixels(pixels: &[u32], width: usize, texture: &mut sdl2::render::Texture) {
+    let height = pixels.len() / width;
     texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
         // println!("pitch is: {:}", pitch);
-        for row in 0..240 {
+        for row in 0..height {
             let offset1 = row * pitch;
-            for col in 0..256 {
+            for col in 0..width {
                 let offset2 = col * 3;
-                let pixel = screen[row][col];
+                let pixel = pixels[row * width + col];
                 let r = (pixel >> 16) as u8;
                 let g = ((pixel >> 8) & 0xff) as u8;
                 let b = (pixel & 0xff) as u8;
@@ -324,11 +1005,6 @@ fn render_frame(screen: &[[u32; 256]; 240],
             }
         }
     }).unwrap();
-
-    renderer.clear();
-    renderer.copy(&texture, None, None);
-    renderer.present();
-
 }
 
 
@@ -0,0 +1,112 @@
+// Per-channel volume, mute and panning. The nonlinear mix is worked out as
+// usual and then split between the channels that went into it, so with the
+// default settings the output is exactly the hardware mix.
+use config::Config;
+
+pub const CHANNEL_NAMES: [&str; 6] = ["pulse1", "pulse2", "triangle", "noise", "dmc", "expansion"];
+pub const PULSE1: usize = 0;
+pub const PULSE2: usize = 1;
+pub const TRIANGLE: usize = 2;
+pub const NOISE: usize = 3;
+pub const DMC: usize = 4;
+pub const EXPANSION: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelSettings {
+    pub enabled: bool,
+    // 0.0 to 2.0
+    pub volume: f32,
+    // -1.0 is hard left, 1.0 hard right
+    pub pan: f32,
+}
+
+pub struct Mixer {
+    pub channels: [ChannelSettings; 6],
+    pub stereo: bool,
+    // (left, right) multiplier for each channel, left only in mono
+    gains: [(f32, f32); 6],
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        let mut mixer = Mixer {
+            channels: [ChannelSettings {
+                enabled: true,
+                volume: 1.0,
+                pan: 0.0,
+            }; 6],
+            stereo: false,
+            gains: [(1.0, 1.0); 6],
+        };
+        mixer.update();
+        mixer
+    }
+
+    // [mixer] stereo = true, then [mixer.pulse1] enabled/volume/pan
+    pub fn from_config(config: &Config) -> Mixer {
+        let mut mixer = Mixer::new();
+        if let Some(stereo) = config.get_bool(&["mixer"], "stereo") {
+            mixer.stereo = stereo;
+        }
+        for (n, name) in CHANNEL_NAMES.iter().enumerate() {
+            let section = ["mixer", name];
+            let channel = &mut mixer.channels[n];
+            if let Some(enabled) = config.get_bool(&section, "enabled") {
+                channel.enabled = enabled;
+            }
+            if let Some(volume) = config.get_float(&section, "volume") {
+                channel.volume = (volume as f32).clamp(0.0, 2.0);
+            }
+            if let Some(pan) = config.get_float(&section, "pan") {
+                channel.pan = (pan as f32).clamp(-1.0, 1.0);
+            }
+        }
+        mixer.update();
+        mixer
+    }
+
+    pub fn channel_index(name: &str) -> Option<usize> {
+        CHANNEL_NAMES.iter().position(|&channel| channel == name)
+    }
+
+    pub fn output_channels(&self) -> usize {
+        if self.stereo {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn toggle(&mut self, channel: usize) -> bool {
+        self.channels[channel].enabled = !self.channels[channel].enabled;
+        self.update();
+        self.channels[channel].enabled
+    }
+
+    // has to be called after changing the settings
+    pub fn update(&mut self) {
+        for (gain, channel) in self.gains.iter_mut().zip(self.channels.iter()) {
+            let level = if channel.enabled {
+                channel.volume
+            } else {
+                0.0
+            };
+            *gain = if self.stereo {
+                (level * (1.0 - channel.pan).clamp(0.0, 1.0),
+                 level * (1.0 + channel.pan).clamp(0.0, 1.0))
+            } else {
+                (level, level)
+            };
+        }
+    }
+
+    pub fn mix(&self, outputs: &[f32; 6]) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (output, &(left_gain, right_gain)) in outputs.iter().zip(self.gains.iter()) {
+            left += output * left_gain;
+            right += output * right_gain;
+        }
+        (left, right)
+    }
+}
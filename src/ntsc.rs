@@ -0,0 +1,224 @@
+// Composite video filter working from the PPU's raw 9 bit pixels.
+// Each scanline is turned back into the signal the PPU generates (8 samples
+// per pixel, 12 samples per colour subcarrier cycle) and decoded again
+// the way a TV would, which gives dot crawl, colour bleeding and artifact
+// colours for free.
+use palette::{ntsc_signal, ntsc_phase, NtscParams};
+
+// smallest output width that keeps all of the horizontal detail
+pub const NTSC_MIN_WIDTH: usize = 602;
+
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = 256 * SAMPLES_PER_PIXEL;
+// wide enough for the largest decode window either side of the line
+const PADDING: usize = 24;
+
+pub struct NtscSettings {
+    // -1.0 to 1.0, edge enhancement of the luma
+    pub sharpness: f32,
+    // -1.0 to 1.0, luma bandwidth, higher is more detail but more artifacts
+    pub resolution: f32,
+    // average two fields to get rid of the dot crawl
+    pub merge_fields: bool,
+    pub width: usize,
+}
+
+impl NtscSettings {
+    pub fn new() -> NtscSettings {
+        NtscSettings {
+            sharpness: 0.0,
+            resolution: 0.0,
+            merge_fields: false,
+            width: NTSC_MIN_WIDTH,
+        }
+    }
+}
+
+pub struct NtscFilter {
+    pub width: usize,
+    sharpness: f32,
+    merge_fields: bool,
+    luma_window: usize,
+    chroma_window: usize,
+
+    // signal level for every pixel value at each of the 12 phases
+    levels: Vec<[f32; 12]>,
+    cos_table: [f32; 12],
+    sin_table: [f32; 12],
+
+    contrast: f32,
+    brightness: f32,
+    saturation: f32,
+    gamma_table: Vec<u8>,
+
+    // running sums of the line's signal so every window is one subtraction
+    sum_y: Vec<f32>,
+    sum_i: Vec<f32>,
+    sum_q: Vec<f32>,
+    line: Vec<(f32, f32, f32)>,
+
+    pub output: Vec<u32>,
+}
+
+const GAMMA_STEPS: usize = 1024;
+
+impl NtscFilter {
+    pub fn new(settings: &NtscSettings, params: &NtscParams) -> NtscFilter {
+        let width = if settings.width < NTSC_MIN_WIDTH {
+            NTSC_MIN_WIDTH
+        } else {
+            settings.width
+        };
+
+        let mut levels = Vec::with_capacity(512);
+        for pixel in 0..512 {
+            let mut phases = [0.0; 12];
+            for (phase, level) in phases.iter_mut().enumerate() {
+                *level = ntsc_signal(pixel as u16, phase);
+            }
+            levels.push(phases);
+        }
+
+        let mut cos_table = [0.0; 12];
+        let mut sin_table = [0.0; 12];
+        for phase in 0..12 {
+            let angle = ntsc_phase(phase as f32, params.hue);
+            cos_table[phase] = angle.cos();
+            sin_table[phase] = angle.sin();
+        }
+
+        let mut gamma_table = Vec::with_capacity(GAMMA_STEPS + 1);
+        for step in 0..GAMMA_STEPS + 1 {
+            let value = step as f32 / GAMMA_STEPS as f32;
+            gamma_table.push(::palette::gamma_fix(value, params.gamma) as u8);
+        }
+
+        // one subcarrier cycle of luma cancels out the chroma completely,
+        // anything narrower lets some of it through as dot crawl
+        let resolution = settings.resolution.clamp(-1.0, 1.0);
+        let luma_window = (12.0 - 6.0 * resolution).round() as usize;
+
+        NtscFilter {
+            width,
+            sharpness: settings.sharpness.clamp(-1.0, 1.0),
+            merge_fields: settings.merge_fields,
+            luma_window,
+            chroma_window: 24,
+
+            levels,
+            cos_table,
+            sin_table,
+
+            contrast: params.contrast,
+            brightness: params.brightness,
+            saturation: params.saturation,
+            gamma_table,
+
+            sum_y: vec![0.0; LINE_SAMPLES + PADDING * 2 + 1],
+            sum_i: vec![0.0; LINE_SAMPLES + PADDING * 2 + 1],
+            sum_q: vec![0.0; LINE_SAMPLES + PADDING * 2 + 1],
+            line: vec![(0.0, 0.0, 0.0); width],
+
+            output: vec![0; width * 240],
+        }
+    }
+
+    // output is width x 240 RGB
+    pub fn filter(&mut self, screen: &[[u16; 256]; 240], framecount: usize) {
+        // a scanline is 341 * 8 samples so each line starts 4 phases later.
+        // A full frame moves the phase on by 4 too, and the dot skipped on
+        // odd frames is 8 samples, so frames alternate between two phases 4
+        // apart. Merging fields always decodes both frame phases.
+        let frame_phase = if self.merge_fields {
+            0
+        } else {
+            (framecount & 1) * 4
+        };
+
+        for (row, pixels) in screen.iter().enumerate() {
+            let phase = (frame_phase + row * 4) % 12;
+            self.decode_line(pixels, phase, false);
+            if self.merge_fields {
+                self.decode_line(pixels, (phase + 4) % 12, true);
+            }
+
+            let start = row * self.width;
+            for x in 0..self.width {
+                let (mut y, mut i, mut q) = self.line[x];
+                if self.merge_fields {
+                    y /= 2.0;
+                    i /= 2.0;
+                    q /= 2.0;
+                }
+                self.output[start + x] = self.yiq_to_rgb(y, i, q);
+            }
+        }
+    }
+
+    // decodes one line into self.line, adding to it when merging fields
+    fn decode_line(&mut self, pixels: &[u16; 256], first_phase: usize, merge: bool) {
+        // the border around the picture is the backdrop colour
+        let border = pixels[0];
+        let mut y = 0.0;
+        let mut i = 0.0;
+        let mut q = 0.0;
+        self.sum_y[0] = 0.0;
+        self.sum_i[0] = 0.0;
+        self.sum_q[0] = 0.0;
+        for n in 0..LINE_SAMPLES + PADDING * 2 {
+            let pixel = if !(PADDING..LINE_SAMPLES + PADDING).contains(&n) {
+                border
+            } else {
+                pixels[(n - PADDING) / SAMPLES_PER_PIXEL]
+            };
+            let phase = (first_phase + n + 12 - (PADDING % 12)) % 12;
+            let signal = self.levels[(pixel & 0x1FF) as usize][phase];
+            y += signal;
+            i += signal * self.cos_table[phase];
+            q += signal * self.sin_table[phase];
+            self.sum_y[n + 1] = y;
+            self.sum_i[n + 1] = i;
+            self.sum_q[n + 1] = q;
+        }
+
+        for x in 0..self.width {
+            let center = PADDING + (x * LINE_SAMPLES + LINE_SAMPLES / 2) / self.width;
+
+            let luma = window(&self.sum_y, center, self.luma_window);
+            let wide_luma = window(&self.sum_y, center, self.luma_window * 2);
+            let luma = luma + (luma - wide_luma) * self.sharpness;
+            // the chroma correlation is half the subcarrier amplitude
+            let chroma_i = window(&self.sum_i, center, self.chroma_window) * 2.0;
+            let chroma_q = window(&self.sum_q, center, self.chroma_window) * 2.0;
+
+            if merge {
+                let (y, i, q) = self.line[x];
+                self.line[x] = (y + luma, i + chroma_i, q + chroma_q);
+            } else {
+                self.line[x] = (luma, chroma_i, chroma_q);
+            }
+        }
+    }
+
+    fn yiq_to_rgb(&self, y: f32, i: f32, q: f32) -> u32 {
+        let y = y * self.contrast + self.brightness;
+        let i = i * self.saturation;
+        let q = q * self.saturation;
+
+        let r = self.gamma(y + 0.956 * i + 0.621 * q);
+        let g = self.gamma(y - 0.272 * i - 0.647 * q);
+        let b = self.gamma(y - 1.106 * i + 1.703 * q);
+        r << 16 | g << 8 | b
+    }
+
+    fn gamma(&self, value: f32) -> u32 {
+        let step = value.clamp(0.0, 1.0) * GAMMA_STEPS as f32;
+        self.gamma_table[step as usize] as u32
+    }
+}
+
+// average of the samples in a window centered on center
+fn window(sums: &[f32], center: usize, size: usize) -> f32 {
+    let start = center - size / 2;
+    (sums[start + size] - sums[start]) / size as f32
+}
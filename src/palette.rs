@@ -0,0 +1,268 @@
+// Maps the PPU's 9 bit pixels (emphasis << 6 | palette index) to RGB
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::Read;
+
+const BASE_PALETTE: [u32; 64] = [
+    0x656565, 0x002D69, 0x131F7F, 0x3C137C, 0x600B62, 0x730A37, 0x710F07, 0x5A1A00,
+    0x342800, 0x0B3400, 0x003C00, 0x003D10, 0x003840, 0x010101, 0x010101, 0x010101,
+
+    0xAEAEAE, 0x0F63B3, 0x4051D0, 0x7841CC, 0xA736A9, 0xC03470, 0xBD3C30, 0x9F4A00,
+    0x6D5C00, 0x366D00, 0x077704, 0x00793D, 0x00727D, 0x010101, 0x010101, 0x010101,
+
+    0xFEFEFF, 0x5DB3FF, 0x8FA1FF, 0xC890FF, 0xF785FA, 0xFF83C0, 0xFF8B7F, 0xEF9A49,
+    0xBDAC2C, 0x85BC2F, 0x55C753, 0x3CC98C, 0x3EC2CD, 0x4E4E4E, 0x010101, 0x010101,
+
+    0xFEFEFF, 0xBCDFFF, 0xD1D8FF, 0xE8D1FF, 0xFBCDFD, 0xFFCCE5, 0xFFCFCA, 0xF8D5B4,
+    0xE4DCA8, 0xCCE3A9, 0xB9E8B8, 0xAEE8D0, 0xAFE5EA, 0xB6B6B6, 0x010101, 0x010101,
+];
+
+// RGB PPU (2C03) palette, 3 bits per channel
+const RGB_2C03: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+pub const BUILTIN_PALETTES: [&str; 3] = ["default", "2c03", "ntsc"];
+
+// how much the channels that aren't emphasized get darkened
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+pub struct Palette {
+    pub colors: [u32; 512],
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette::from_base(&BASE_PALETTE)
+    }
+
+    // builds the emphasis entries by attenuating the base 64 colours
+    pub fn from_base(base: &[u32; 64]) -> Palette {
+        let mut colors = [0; 512];
+        for emphasis in 0..8 {
+            let mut rf = 1.0;
+            let mut gf = 1.0;
+            let mut bf = 1.0;
+            if emphasis & 1 != 0 {
+                gf *= EMPHASIS_ATTENUATION;
+                bf *= EMPHASIS_ATTENUATION;
+            }
+            if emphasis & 2 != 0 {
+                rf *= EMPHASIS_ATTENUATION;
+                bf *= EMPHASIS_ATTENUATION;
+            }
+            if emphasis & 4 != 0 {
+                rf *= EMPHASIS_ATTENUATION;
+                gf *= EMPHASIS_ATTENUATION;
+            }
+
+            for index in 0..64 {
+                let color = base[index];
+                let r = ((color >> 16) & 0xFF) as f32 * rf;
+                let g = ((color >> 8) & 0xFF) as f32 * gf;
+                let b = (color & 0xFF) as f32 * bf;
+                colors[emphasis << 6 | index] = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+            }
+        }
+        Palette {
+            colors,
+        }
+    }
+
+    pub fn builtin(name: &str, ntsc: &NtscParams) -> Option<Palette> {
+        match name {
+            "default" => Some(Palette::new()),
+            "2c03" => {
+                let mut base = [0; 64];
+                for (index, color) in RGB_2C03.iter().enumerate() {
+                    let r = ((color >> 6) & 7) as u32 * 255 / 7;
+                    let g = ((color >> 3) & 7) as u32 * 255 / 7;
+                    let b = (color & 7) as u32 * 255 / 7;
+                    base[index] = r << 16 | g << 8 | b;
+                }
+                Some(Palette::from_base(&base))
+            }
+            "ntsc" => Some(Palette::from_ntsc(ntsc)),
+            _ => None,
+        }
+    }
+
+    // either a built-in palette name or a path to a .pal file
+    pub fn load(name: &str, ntsc: &NtscParams) -> Result<Palette, String> {
+        match Palette::builtin(name, ntsc) {
+            Some(palette) => Ok(palette),
+            None => Palette::from_file(name),
+        }
+    }
+
+    // 192 byte files hold the 64 base colours, 1536 byte files hold all
+    // 8 emphasis combinations
+    pub fn from_file(path: &str) -> Result<Palette, String> {
+        let mut file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| format!("{}: {}", path, e))?;
+
+        let rgb = |n: usize| {
+            (data[n * 3] as u32) << 16 | (data[n * 3 + 1] as u32) << 8 | data[n * 3 + 2] as u32
+        };
+        match data.len() {
+            192 => {
+                let mut base = [0; 64];
+                for (index, color) in base.iter_mut().enumerate() {
+                    *color = rgb(index);
+                }
+                Ok(Palette::from_base(&base))
+            }
+            1536 => {
+                let mut colors = [0; 512];
+                for (index, color) in colors.iter_mut().enumerate() {
+                    *color = rgb(index);
+                }
+                Ok(Palette {
+                    colors,
+                })
+            }
+            len => Err(format!("{}: expected 192 or 1536 bytes, got {}", path, len)),
+        }
+    }
+
+    // Decodes the composite signal the PPU would generate for each pixel value
+    // http://wiki.nesdev.com/w/index.php/NTSC_video
+    pub fn from_ntsc(params: &NtscParams) -> Palette {
+        let mut colors = [0; 512];
+        for (pixel, color) in colors.iter_mut().enumerate() {
+            let (y, i, q) = ntsc_yiq(pixel as u16, params.hue);
+            let y = y * params.contrast + params.brightness;
+            let i = i * params.saturation;
+            let q = q * params.saturation;
+
+            // FCC YIQ to RGB
+            let r = gamma_fix(y + 0.956 * i + 0.621 * q, params.gamma);
+            let g = gamma_fix(y - 0.272 * i - 0.647 * q, params.gamma);
+            let b = gamma_fix(y - 1.106 * i + 1.703 * q, params.gamma);
+            *color = r << 16 | g << 8 | b;
+        }
+        Palette {
+            colors,
+        }
+    }
+
+    pub fn rgb(&self, pixel: u16) -> u32 {
+        self.colors[(pixel & 0x1FF) as usize]
+    }
+}
+
+
+pub struct NtscParams {
+    // degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    // display gamma, 2.2 leaves the decoded signal as is
+    pub gamma: f32,
+}
+
+impl NtscParams {
+    pub fn new() -> NtscParams {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+// voltage levels relative to sync, the last 8 are with emphasis attenuation
+const SIGNAL_LEVELS: [f32; 16] = [
+    0.228, 0.312, 0.552, 0.880, // signal low
+    0.616, 0.840, 1.100, 1.100, // signal high
+    0.192, 0.256, 0.448, 0.712, // signal low, attenuated
+    0.500, 0.676, 0.896, 0.896, // signal high, attenuated
+];
+const SIGNAL_BLACK: f32 = 0.312;
+const SIGNAL_WHITE: f32 = 1.100;
+
+// lines the decoded hues up with the colour burst
+const HUE_OFFSET: f32 = 3.9;
+
+// the composite level for one of the 12 phases of the colour subcarrier,
+// normalized so black is 0.0 and white is 1.0
+pub fn ntsc_signal(pixel: u16, phase: usize) -> f32 {
+    let color = (pixel & 0x0F) as usize;
+    let mut level = ((pixel >> 4) & 3) as usize;
+    let emphasis = pixel >> 6;
+    if color > 13 {
+        level = 1;
+    }
+
+    let in_phase = |c: usize| (c + phase) % 12 < 6;
+    let attenuate = (emphasis & 1 != 0 && in_phase(0)) ||
+                    (emphasis & 2 != 0 && in_phase(4)) ||
+                    (emphasis & 4 != 0 && in_phase(8));
+    let offset = if attenuate {
+        8
+    } else {
+        0
+    };
+
+    let mut low = SIGNAL_LEVELS[level + offset];
+    let mut high = SIGNAL_LEVELS[4 + level + offset];
+    if color == 0 {
+        low = high;
+    }
+    if color > 12 {
+        high = low;
+    }
+
+    let signal = if in_phase(color) {
+        high
+    } else {
+        low
+    };
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+// phase angle of sample p of the subcarrier, with the hue adjustment
+pub fn ntsc_phase(p: f32, hue: f32) -> f32 {
+    PI * (p + HUE_OFFSET) / 6.0 + hue.to_radians()
+}
+
+fn ntsc_yiq(pixel: u16, hue: f32) -> (f32, f32, f32) {
+    let mut y = 0.0;
+    let mut i = 0.0;
+    let mut q = 0.0;
+    for p in 0..12 {
+        let signal = ntsc_signal(pixel, p);
+        let angle = ntsc_phase(p as f32, hue);
+        y += signal;
+        i += signal * angle.cos();
+        q += signal * angle.sin();
+    }
+    // the chroma correlation is half the subcarrier amplitude
+    (y / 12.0, i / 6.0, q / 6.0)
+}
+
+pub fn gamma_fix(value: f32, gamma: f32) -> u32 {
+    if value <= 0.0 {
+        return 0;
+    }
+    let value = value.powf(2.2 / gamma);
+    if value >= 1.0 {
+        255
+    } else {
+        (value * 255.0 + 0.5) as u32
+    }
+}
@@ -1,21 +1,7 @@
 use cart;
+use events::{EventLog, EventKind};
 // use time;
 
-const PALETTE: [u32; 64] = [
-    0x656565, 0x002D69, 0x131F7F, 0x3C137C, 0x600B62, 0x730A37, 0x710F07, 0x5A1A00,
-    0x342800, 0x0B3400, 0x003C00, 0x003D10, 0x003840, 0x010101, 0x010101, 0x010101,
-
-    0xAEAEAE, 0x0F63B3, 0x4051D0, 0x7841CC, 0xA736A9, 0xC03470, 0xBD3C30, 0x9F4A00,
-    0x6D5C00, 0x366D00, 0x077704, 0x00793D, 0x00727D, 0x010101, 0x010101, 0x010101,
-
-    0xFEFEFF, 0x5DB3FF, 0x8FA1FF, 0xC890FF, 0xF785FA, 0xFF83C0, 0xFF8B7F, 0xEF9A49,
-    0xBDAC2C, 0x85BC2F, 0x55C753, 0x3CC98C, 0x3EC2CD, 0x4E4E4E, 0x010101, 0x010101,
-
-    0xFEFEFF, 0xBCDFFF, 0xD1D8FF, 0xE8D1FF, 0xFBCDFD, 0xFFCCE5, 0xFFCFCA, 0xF8D5B4,
-    0xE4DCA8, 0xCCE3A9, 0xB9E8B8, 0xAEE8D0, 0xAFE5EA, 0xB6B6B6, 0x010101, 0x010101,
-];
-
-
 // #[derive(Debug)]
 pub struct PPU {
     // PPUCTRL $2000
@@ -58,21 +44,50 @@ pub struct PPU {
     vram: Box<[u8]>,
     pub chr: cart::ChrRom,
 
-    pub lastwrite: u8,
+    // the data latch between the CPU and the PPU registers, reading a
+    // write only register returns it. Each bit decays to 0 about 600ms
+    // after it was last driven high.
+    io_latch: u8,
+    io_refreshed: [usize; 8],
+    // frames since power on, rendering or not, for the decay and turbo
+    pub elapsed_frames: usize,
     ppudata_buffer: u8,
     initial_reset: bool,
     nmi_generated: bool,
 
-    bg_prerender: [u8; 256],
-    sprite0_prerender: [u8; 8],
-    sprite_render: [(u32, bool); 256],
-
-    pub screen: [[u32; 256]; 240],
+    // sprite evaluation state for the next scanline
+    secondary_oam: [u8; 32],
+    sprite_eval_n: usize,
+    sprite_eval_m: usize,
+    sprite_eval_count: usize,
+    sprite_eval_done: bool,
+    sprite0_in_range: bool,
+    sprite_count: usize,
+    secondary_index: [u8; 8],
+    // (palette entry, behind background, is sprite 0) per pixel of the line
+    sprite_line: [(u8, bool, bool); 256],
+    // what is actually drawn when the display options below are in use
+    display_sprite_line: [(u8, bool, bool); 256],
+    use_display_sprite_line: bool,
+
+    // display only options, these never change what the game sees
+    pub no_sprite_limit: bool,
+    pub hide_bg: bool,
+    pub hide_sprites: bool,
+    // bit n hides OAM entry n
+    pub hidden_sprites: u64,
+
+    // palette index in the low 6 bits, PPUMASK emphasis bits in the top 3
+    pub screen: [[u16; 256]; 240],
 
     pub framecount: usize,
     pub extra_cycle: bool,
+    // set when the last visible line is done, the frontend clears it
+    pub frame_ready: bool,
     pub cycles: isize,
     bg_column: isize,
+
+    pub events: EventLog,
 }
 
 impl PPU {
@@ -124,27 +139,44 @@ impl PPU {
             vram: vec![0; 1024 * 4].into_boxed_slice(),
             chr: chr,
 
-            lastwrite: 0,
+            io_latch: 0,
+            io_refreshed: [0; 8],
+            elapsed_frames: 0,
             ppudata_buffer: 0,
             initial_reset: true,
             nmi_generated: false,
 
             screen: [[0; 256]; 240],
-            bg_prerender: [0; 256],
-            sprite0_prerender: [0; 8],
-            sprite_render: [(0, false); 256],
-            // sprite0_prerender: [(0, false); 256],
+            secondary_oam: [0xFF; 32],
+            sprite_eval_n: 0,
+            sprite_eval_m: 0,
+            sprite_eval_count: 0,
+            sprite_eval_done: false,
+            sprite0_in_range: false,
+            sprite_count: 0,
+            secondary_index: [0; 8],
+            sprite_line: [(0, false, false); 256],
+            display_sprite_line: [(0, false, false); 256],
+            use_display_sprite_line: false,
+
+            no_sprite_limit: false,
+            hide_bg: false,
+            hide_sprites: false,
+            hidden_sprites: 0,
 
             framecount: 0,
             extra_cycle: false,
+            frame_ready: false,
             cycles: 0,
             bg_column: 0,
+
+            events: EventLog::new(),
         }
     }
 
     // $2000
     pub fn write_ppuctrl(&mut self, data: u8){
-        self.lastwrite = data;
+        self.refresh_io(data, 0xFF);
 
         self.t_vram_addr &= 0x73FF;
         self.t_vram_addr |= (data as u16 & 3) << 10;
@@ -164,7 +196,7 @@ impl PPU {
 
     // $2001
     pub fn write_ppumask(&mut self, data: u8){
-        self.lastwrite = data;
+        self.refresh_io(data, 0xFF);
         // println!("Write PPUMASK {:#b}", data);
         self.grayscale = (data & (1 << 0)) != 0;
         self.bg_left_8px = (data & (1 << 1)) != 0;
@@ -176,6 +208,39 @@ impl PPU {
         self.emphasize_blue = (data & (1 << 7)) != 0;
     }
 
+    // about 600ms
+    const IO_DECAY_FRAMES: usize = 36;
+
+    // bits of mask are driven onto the latch from value
+    fn refresh_io(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_refreshed[bit] = self.elapsed_frames;
+            }
+        }
+    }
+
+    // the latch with any bits that have been left too long decayed
+    fn io_bus(&mut self) -> u8 {
+        for bit in 0..8 {
+            if self.elapsed_frames - self.io_refreshed[bit] > PPU::IO_DECAY_FRAMES {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+        self.io_latch
+    }
+
+    // reads of $2000, $2001, $2003, $2005 and $2006
+    pub fn read_open_bus(&mut self) -> u8 {
+        self.io_bus()
+    }
+
+    // writing $2002 does nothing but fill the latch
+    pub fn write_ppustatus(&mut self, data: u8) {
+        self.refresh_io(data, 0xFF);
+    }
+
     // $2002
     pub fn read_ppustatus(&mut self) -> u8{
         let mut value:u8 = 0;
@@ -191,18 +256,21 @@ impl PPU {
             self.nmi_generated = false;
         }
         self.w_toggle = false;
-        value | (self.lastwrite & 0b11111)
+        // only the top 3 bits are driven, the rest is whatever was on the latch
+        let value = value | (self.io_bus() & 0x1F);
+        self.refresh_io(value, 0xE0);
+        value
     }
 
     pub fn write_oamaddr(&mut self, data: u8) {
-        self.lastwrite = data;
+        self.refresh_io(data, 0xFF);
         self.oam_addr = data;
         // println!("OAMADDR set: {:#X}", data);
     }
 
     // $2004
     pub fn write_oamdata(&mut self, data: u8) {
-        self.lastwrite = data;
+        self.refresh_io(data, 0xFF);
         self.oam[self.oam_addr as usize] = data;
         // println!("OAMDATA set: {:#X} at {:#X}", data, self.oam_addr);
         self.oam_addr = self.oam_addr.wrapping_add(1);
@@ -210,12 +278,25 @@ impl PPU {
 
     // $2004
     pub fn read_oamdata(&mut self) -> u8 {
-        self.oam[self.oam_addr as usize]
+        // secondary OAM clear drives the bus to $FF during dots 1-64
+        if (self.show_bg || self.show_sprites) && self.scanline >= 0 &&
+            self.scanline < 240 && self.cycles >= 1 && self.cycles <= 64
+        {
+            self.refresh_io(0xFF, 0xFF);
+            return 0xFF;
+        }
+        let mut value = self.oam[self.oam_addr as usize];
+        // bits 2-4 of the sprite attributes don't exist
+        if self.oam_addr & 3 == 2 {
+            value &= 0xE3;
+        }
+        self.refresh_io(value, 0xFF);
+        value
     }
 
     // $2005
     pub fn write_ppuscroll(&mut self, data: u8) {
-        self.lastwrite = data;
+        self.refresh_io(data, 0xFF);
         if !self.w_toggle {
             self.fine_x = data & 0x7;
         //    println!("Finex set to {} during SL {} at {}", self.fine_x, self.scanline, self.cycles);
@@ -234,10 +315,11 @@ impl PPU {
 
     // $2006
     pub fn write_ppuaddr(&mut self, data: u8) {
-        self.lastwrite = data;
+        self.refresh_io(data, 0xFF);
         if !self.w_toggle {
+            // the first write clears bit 14 as well
             self.t_vram_addr &= 0x00FF;
-            self.t_vram_addr |= (data as u16 & 0x7F) << 8;
+            self.t_vram_addr |= (data as u16 & 0x3F) << 8;
             // self.ppu_addr = (data as u16) << 8;
         //    println!("first write to PPUADDR: {:#X} after sl {}", data, self.scanline);
         } else {
@@ -258,60 +340,91 @@ impl PPU {
 
     // TODO - mappers
     pub fn write_ppudata(&mut self, data:u8) {
-        self.lastwrite = data;
-        let v_addr = self.vram_addr;
+        self.refresh_io(data, 0xFF);
+        // the PPU only has 14 address lines
+        let v_addr = self.vram_addr & 0x3FFF;
         // println!("write PPUDATA {:#x} at virtual addr {:#X}", data, self.vram_addr);
         match v_addr {
             0x0000...0x1FFF => self.chr.write_u8(v_addr, data),
-            0x2000...0x2FFF => self.vram[self.map_vram(v_addr)] = data,
-            0x3000...0x3EFF => panic!("Need mirrors of 0x2000-0x2EFF"),
-            0x3F00...0x3FFF => {
-                let mut realaddr = (self.vram_addr - 0x3F00) % 0x20;
-                if realaddr == 0x10 || realaddr == 0x14 || realaddr == 0x18 || realaddr == 0x1C {
-                    realaddr -= 0x10;
-                }
-                // println!("Writing palette data {:#x} at {:#x} ({:#X})", data, realaddr, self.ppu_addr);
-                self.palette[realaddr as usize] = data;
-            }
-            _ => panic!("need mirrors of all vram")
+            0x2000...0x3EFF => self.vram[self.map_vram(v_addr)] = data,
+            _ => self.palette[palette_addr(v_addr)] = data & 0x3F,
         }
-        if !self.vram_increment {
-            self.vram_addr += 1;
+        self.increment_vram_addr();
+    }
+
+    fn increment_vram_addr(&mut self) {
+        let step = if self.vram_increment {
+            32
         } else {
-            // not sure of this...
-            self.vram_addr += 32;
-        }
+            1
+        };
+        // v is 15 bits, accesses ignore the top bit so $3FFF wraps to $0000
+        self.vram_addr = (self.vram_addr + step) & 0x7FFF;
     }
 
     fn read_data(&self, addr: u16) -> u8 {
         //        println!("read from {:#X}", addr);
+        let addr = addr & 0x3FFF;
         match addr {
             0x0000...0x1FFF => self.chr.read_u8(addr),
-            0x2000...0x2FFF => self.vram[self.map_vram(addr)],
-            0x3000...0x3EFF => panic!("Need mirrors of 0x2000-0x2EFF"),
-            0x3F00...0x3FFF => {
-                let mut realaddr = (addr - 0x3F00) % 0x20;
-                if realaddr == 0x10 || realaddr == 0x14 || realaddr == 0x18 || realaddr == 0x1C {
-                    realaddr -= 0x10;
-                }
-                self.palette[realaddr as usize]
-            }
-            _ => panic!("need mirrors of all vram")
+            // $3000-$3EFF mirrors the nametables
+            0x2000...0x3EFF => self.vram[self.map_vram(addr)],
+            _ => self.palette[palette_addr(addr)],
+        }
+    }
+
+    // side effect free reads for the debug viewers
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read_data(addr)
+    }
 
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn bg_table(&self) -> u16 {
+        if self.bg_table_high {
+            0x1000
+        } else {
+            0
         }
     }
 
+    pub fn sprite_table(&self) -> u16 {
+        if self.sprite_table_high {
+            0x1000
+        } else {
+            0
+        }
+    }
+
+    pub fn sprite_8x16(&self) -> bool {
+        self.sprite_8x16
+    }
+
+    // scroll position from t, within the 512x480 nametable area
+    pub fn scroll(&self) -> (usize, usize) {
+        let t = self.t_vram_addr as usize;
+        let x = (t >> 10 & 1) * 256 + (t & 0x1F) * 8 + self.fine_x as usize;
+        let y = (t >> 11 & 1) * 240 + (t >> 5 & 0x1F) * 8 + (t >> 12 & 7);
+        (x, y)
+    }
+
+    // tags an event with the current position for the event viewer
+    pub fn log_event(&mut self, kind: EventKind) {
+        self.events.record(self.scanline, self.cycles, kind);
+    }
+
     fn map_vram (&self, addr: u16) -> usize {
+        let addr = addr & 0xFFF;
         if self.chr.vertical_mirroring {
             (addr & 0x7FF) as usize
         } else if self.chr.horizontal_mirroring {
-            if addr < 0x2800 {
-                (addr & 0x3FF) as usize
-            } else {
-                (addr & 0x3FF | 0x400) as usize
-            }
+            (addr & 0x3FF | (addr & 0x800) >> 1) as usize
         } else if self.chr.four_screen_vram {
-            (addr - 0x2000) as usize
+            addr as usize
+        } else if self.chr.one_screen_upper {
+            (addr & 0x3FF | 0x400) as usize
         } else { // single screen??
             (addr & 0x3FF) as usize
             // TODO: this will need more logic I think
@@ -320,25 +433,30 @@ impl PPU {
     }
 
     pub fn read_ppudata(&mut self) -> u8 {
-        let tmp = self.vram_addr;
+        let tmp = self.vram_addr & 0x3FFF;
         let data = self.read_data(tmp);
 
         // println!("Read PPUDATA {:#X} from {:#X}", data, self.vram_addr);
 
-        if !self.vram_increment {
-            self.vram_addr += 1;
-        } else {
-            // not sure of this...
-            self.vram_addr += 32;
-        }
+        self.increment_vram_addr();
 
-        if tmp >= 0x3F00 && tmp <= 0x3FFF {
+        if tmp >= 0x3F00 {
             // println!("palette read {:#X} from {:#X}", data, tmp);
+            // palette reads skip the buffer, which gets the nametable
+            // byte underneath instead. Palette RAM is only 6 bits wide so
+            // the top 2 come from the latch.
             self.ppudata_buffer = self.read_data(tmp - 0x1000);
-            data
+            let mut value = data & 0x3F;
+            if self.grayscale {
+                value &= 0x30;
+            }
+            let value = value | (self.io_bus() & 0xC0);
+            self.refresh_io(value, 0x3F);
+            value
         } else {
             let ret = self.ppudata_buffer;
             self.ppudata_buffer = data;
+            self.refresh_io(ret, 0xFF);
             ret
         }
     }
@@ -354,6 +472,7 @@ impl PPU {
                 self.scanline += 1;
                 if self.scanline > 260 {
                     self.scanline = -1;
+                    self.elapsed_frames += 1;
                 }
             }
             if self.scanline == -1 && self.show_bg && self.cycles == 340 {
@@ -365,6 +484,12 @@ impl PPU {
                 self.framecount += 1;
                 // println!("Frame# {}", self.framecount);
             }
+            if self.scanline == 0 && self.cycles == 0 {
+                self.events.end_frame();
+            }
+            if self.scanline == 240 && self.cycles == 0 {
+                self.frame_ready = true;
+            }
 
             if self.cycles == 260 && (self.show_bg || self.show_sprites) &&
                                 self.scanline >= -1 && self.scanline < 240
@@ -377,6 +502,7 @@ impl PPU {
                 if self.scanline == -1 {
                     self.nmi_generated = false;
                     self.sprite0_hit = false;
+                    self.sprite_overflow = false;
                     self.vblank  = false;
                     if self.initial_reset {self.initial_reset = false};
                 }
@@ -387,39 +513,50 @@ impl PPU {
 
             if self.scanline >= 0 && self.scanline < 240 {
                 if self.cycles == 0 && self.show_bg {
-                    let bgcolor = PALETTE[self.palette[0] as usize % 64];
+                    let bgcolor = self.pixel_value(0);
                     self.screen[self.scanline as usize] = [bgcolor; 256];
-                    if !self.sprite0_hit && self.show_bg && self.show_sprites {
-                        self.sprite0_prerender = [0; 8];
-                        self.bg_prerender = [0; 256];
-                    }
-                    self.sprite_render = [(0, false); 256];
                 }
 
 
-                if self.cycles <= 256 && self.cycles % 8 == 0 && self.show_bg {
-                    self.render_8pxbg();
+                if self.cycles <= 256 && self.cycles % 8 == 0 &&
+                    (self.show_bg || self.show_sprites)
+                {
+                    self.render_8px();
                     self.bg_column += 1;
-                    if self.show_sprites {
-                        self.render_sprites();
-                    }
                 }
 
                 if self.cycles > 2 && self.sprite0_to_be_hit {
+                    if !self.sprite0_hit {
+                        self.events.record(self.scanline, self.cycles, EventKind::Sprite0Hit);
+                    }
                     self.sprite0_hit = true;
                     // println!("Sprite0 hit frame {}", self.framecount);
                     self.sprite0_to_be_hit = false;
                 }
 
+                if self.show_bg || self.show_sprites {
+                    if self.cycles == 1 {
+                        self.secondary_oam = [0xFF; 32];
+                    }
+                    if self.cycles == 65 {
+                        self.sprite_eval_n = 0;
+                        self.sprite_eval_m = 0;
+                        self.sprite_eval_count = 0;
+                        self.sprite_eval_done = false;
+                        self.sprite0_in_range = false;
+                    }
+                    // odd dots read OAM, even dots write secondary OAM
+                    if self.cycles >= 65 && self.cycles <= 256 && self.cycles % 2 == 0 {
+                        self.evaluate_sprite_step();
+                    }
+                }
+
                 if self.cycles == 257 && (self.show_bg || self.show_sprites)
                 {
-                    // mux screen and sprites here?
-                    for x in 0..256 {
-                        let (px, background) = self.sprite_render[x];
-                        if px != 0 && (!background || (background && self.bg_prerender[x] == 0)) {
-                            self.screen[self.scanline as usize][x] = px;
-                        }
-                    }
+                    self.sprite_count = self.sprite_eval_count;
+                    self.fetch_sprites();
+                    self.oam_addr = 0;
+
                     self.increment_y();
                     // copy horizontal bits from t to v
                     self.vram_addr &= 0x7BE0;
@@ -428,6 +565,12 @@ impl PPU {
                 }
             }
 
+            // no sprites are ever drawn on the first visible line
+            if self.scanline == -1 && self.cycles == 257 {
+                self.sprite_count = 0;
+                self.sprite_line = [(0, false, false); 256];
+                self.use_display_sprite_line = false;
+            }
 
             if self.scanline == -1 && (self.show_bg || self.show_sprites)
                 && (self.cycles >= 280 && self.cycles <= 304)
@@ -449,14 +592,14 @@ impl PPU {
 
     }
 
-    fn render_8pxbg(&mut self) {
-        // TODO: use left 8px setting
+    fn render_8px(&mut self) {
         /*
         read the nametable at the base nametable address to lookup the tile
         read the attribute table after that to lookup the palette
         read the looked-up tile data which is stored as a bit-pair of bytes
         calculate the actual pixel colours from the bit-pair
-        then use the palette data to assign each pixel a colour based on the calculation
+        then mux with the sprite pixels fetched for this line and
+        use the palette data to assign each pixel a colour
         */
         // using the PPU's actual functions for this should be correct.
 
@@ -499,107 +642,239 @@ impl PPU {
         for mut px in 0..8 {
             let pixel_x = (self.bg_column * 8) - self.fine_x as isize + px as isize;
         //    println!("PixelX {}", pixel_x);
-            if pixel_x <= 255 && pixel_x >= 0 && (self.bg_left_8px || (!self.bg_left_8px && pixel_x > 7)){
-                px = 7 - px;
-                let pv = ((tile_data2 & (1 << px)) >> px) << 1 | (tile_data1 & (1 << px)) >> px;
-                let pixel = if pv > 0 {
-                    PALETTE[self.palette[pv as usize + (attr as usize * 4)] as usize % 64]
+            if !(0..=255).contains(&pixel_x) {
+                continue;
+            }
+            px = 7 - px;
+            let mut pv = ((tile_data2 & (1 << px)) >> px) << 1 | (tile_data1 & (1 << px)) >> px;
+            if !self.show_bg || (!self.bg_left_8px && pixel_x < 8) {
+                pv = 0;
+            }
+
+            let sprites_clipped = !self.show_sprites || (!self.sprite_left_8px && pixel_x < 8);
+            let (mut sprite_pv, mut behind, sprite0) = self.sprite_line[pixel_x as usize];
+            if sprites_clipped {
+                sprite_pv = 0;
+            }
+
+            if sprite0 && sprite_pv != 0 && pv != 0 && pixel_x < 255 {
+                self.sprite0_to_be_hit = true;
+            }
+
+            // past this point only what ends up on screen is affected
+            if self.use_display_sprite_line {
+                let (display_pv, display_behind, _) = self.display_sprite_line[pixel_x as usize];
+                sprite_pv = if sprites_clipped {
+                    0
                 } else {
-                    PALETTE[self.palette[0] as usize % 64]
+                    display_pv
                 };
+                behind = display_behind;
+            }
+            if self.hide_sprites {
+                sprite_pv = 0;
+            }
+            if self.hide_bg {
+                pv = 0;
+            }
 
-                if pv > 0 &&
-                    pixel_x as u8 >= self.oam[3] &&
-                    pixel_x <= (self.oam[3] as isize + 7) &&
-                    pixel_x < 255 &&
-                    self.sprite0_prerender[(pixel_x - self.oam[3] as isize) as usize] != 0
-                {
-                    self.sprite0_to_be_hit = true;
-                }
+            // the first opaque sprite wins even when it's behind the background
+            let entry = if sprite_pv != 0 && (!behind || pv == 0) {
+                sprite_pv as usize
+            } else if pv > 0 {
+                pv as usize + (attr as usize * 4)
+            } else {
+                0
+            };
 
-                self.screen[sl as usize][pixel_x as usize] = pixel;
-                self.bg_prerender[pixel_x as usize] = pv;
-            }
+            self.screen[sl as usize][pixel_x as usize] = self.pixel_value(entry);
         }
         // let end = time::precise_time_ns();
         self.increment_x();
     }
 
-    fn render_sprites(&mut self) {
-        // TODO: deal with more than 8 sprites on a scanline
-        // TODO: 8x16 sprites
-        let sl = self.scanline;
+    // 9 bit output pixel: emphasis << 6 | palette index, greyscale masks off the hue
+    fn pixel_value(&self, entry: usize) -> u16 {
+        let mut index = (self.palette[entry] & 0x3F) as u16;
+        if self.grayscale {
+            index &= 0x30;
+        }
+        let mut emphasis = 0;
+        if self.emphasize_red {
+            emphasis |= 1;
+        }
+        if self.emphasize_green {
+            emphasis |= 2;
+        }
+        if self.emphasize_blue {
+            emphasis |= 4;
+        }
+        emphasis << 6 | index
+    }
 
-        for mut sprite in 0..64 {
-            sprite = 63 - sprite;
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let height = if self.sprite_8x16 {
+            16
+        } else {
+            8
+        };
+        let row = self.scanline - y as i16;
+        row >= 0 && row < height
+    }
 
-            let y = self.oam[sprite * 4] as i16 + 1;
-            let vert = if self.sprite_8x16 {
-                15
-            } else {
-                7
-            };
-            // println!("sprite {:} is at {:}", sprite, y);
-            if sl >= y && sl <= y + vert {
-                let mut index = self.oam[(sprite * 4) + 1] as u16 * 16;
-                if self.sprite_table_high && !self.sprite_8x16 {
-                    index += 0x1000;
-                }
-                if self.sprite_8x16 {
-                    let mut idx = self.oam[(sprite * 4) + 1];
-                    if idx & 1 == 1 {
-                        idx &= 0xFE;
-                        index = (idx as u16 * 16) + 0x1000;
+    // One read/write pair of the secondary OAM evaluation (dots 65-256)
+    fn evaluate_sprite_step(&mut self) {
+        if self.sprite_eval_done {
+            return;
+        }
+
+        let data = self.oam[self.sprite_eval_n * 4 + self.sprite_eval_m];
+        if self.sprite_eval_count < 8 {
+            self.secondary_oam[self.sprite_eval_count * 4 + self.sprite_eval_m] = data;
+            if self.sprite_eval_m == 0 {
+                if self.sprite_in_range(data) {
+                    if self.sprite_eval_n == 0 {
+                        self.sprite0_in_range = true;
                     }
+                    self.secondary_index[self.sprite_eval_count] = self.sprite_eval_n as u8;
+                    self.sprite_eval_m = 1;
+                } else {
+                    self.sprite_eval_n += 1;
                 }
-                let pal = 0x11 + (self.oam[(sprite * 4) + 2] & 0b11) * 4;
+            } else {
+                self.sprite_eval_m += 1;
+                if self.sprite_eval_m == 4 {
+                    self.sprite_eval_m = 0;
+                    self.sprite_eval_count += 1;
+                    self.sprite_eval_n += 1;
+                }
+            }
+        } else {
+            // the hardware bug: after 8 sprites are found m is incremented
+            // along with n, so the wrong bytes get treated as Y coordinates
+            if self.sprite_in_range(data) {
+                self.sprite_overflow = true;
+                self.sprite_eval_done = true;
+            } else {
+                self.sprite_eval_n += 1;
+                self.sprite_eval_m = (self.sprite_eval_m + 1) & 3;
+            }
+        }
 
-                let background = (self.oam[(sprite * 4) + 2] & 0x20) != 0;
+        if self.sprite_eval_n >= 64 {
+            self.sprite_eval_done = true;
+        }
+    }
 
-                let flip_h = (self.oam[(sprite * 4) + 2] & (1 << 6)) != 0;
-                let flip_v = (self.oam[(sprite * 4) + 2] & (1 << 7)) != 0;
-                let x = self.oam[(sprite * 4) + 3];
-                // println!("Sprite {:} is {:?} on {:},{:}", sprite, bg, y, x);
+    // Sprite pattern fetches (dots 257-320) for the sprites found by evaluation
+    fn fetch_sprites(&mut self) {
+        self.sprite_line = [(0, false, false); 256];
 
-                let offset = if flip_v {
-                    vert - (sl - y)
-                } else {
-                    sl - y
-                };
-                //println!("offset {}", offset);
-                let sprite_data1: u8;
-                let sprite_data2: u8;
-                if offset <= 7 {
-                    sprite_data1 = self.read_data(index + offset as u16);
-                    sprite_data2 = self.read_data(index + 8 + offset as u16);
-                } else {
-                    sprite_data1 = self.read_data(index + 8 + offset as u16);;
-                    sprite_data2 = self.read_data(index + 16 + offset as u16);;
+        for sprite in 0..self.sprite_count {
+            let y = self.secondary_oam[sprite * 4];
+            let tile = self.secondary_oam[(sprite * 4) + 1];
+            let attr = self.secondary_oam[(sprite * 4) + 2];
+            let x = self.secondary_oam[(sprite * 4) + 3];
+
+            let (lo, hi) = self.sprite_pattern(y, tile, attr);
+            let is_sprite0 = sprite == 0 && self.sprite0_in_range;
+            PPU::draw_sprite_row(&mut self.sprite_line, x, attr, lo, hi, is_sprite0);
+        }
+
+        self.use_display_sprite_line = self.no_sprite_limit || self.hidden_sprites != 0;
+        if self.use_display_sprite_line {
+            self.fetch_display_sprites();
+        }
+    }
+
+    // Same as fetch_sprites but for the display options: either every sprite
+    // in range straight from OAM or the evaluated ones, minus hidden entries
+    fn fetch_display_sprites(&mut self) {
+        self.display_sprite_line = [(0, false, false); 256];
+
+        // OAM indexes in priority order, like secondary_index
+        let mut sprites = [0u8; 64];
+        let mut count = 0;
+        if self.no_sprite_limit {
+            for sprite in 0..64 {
+                if self.sprite_in_range(self.oam[sprite * 4]) {
+                    sprites[count] = sprite as u8;
+                    count += 1;
                 }
+            }
+        } else {
+            sprites[..self.sprite_count].copy_from_slice(&self.secondary_index[..self.sprite_count]);
+            count = self.sprite_count;
+        }
 
-                for px in 0..8 {
-                    let pv:u8;
-                    if flip_h {
-                        pv = ((sprite_data2 & (1 << px)) >> px) << 1 | (sprite_data1 & (1 << px)) >> px;
-                    } else {
-                        pv = ((sprite_data2 & (1 << 7 - px)) >> 7 - px) << 1 | (sprite_data1 & (1 << 7 - px)) >> 7 - px;
-                    }
+        for &sprite in &sprites[..count] {
+            let sprite = sprite as usize;
+            if self.hidden_sprites & (1 << sprite) != 0 {
+                continue;
+            }
+            let y = self.oam[sprite * 4];
+            let tile = self.oam[(sprite * 4) + 1];
+            let attr = self.oam[(sprite * 4) + 2];
+            let x = self.oam[(sprite * 4) + 3];
+
+            let (lo, hi) = self.sprite_pattern(y, tile, attr);
+            PPU::draw_sprite_row(&mut self.display_sprite_line, x, attr, lo, hi, false);
+        }
+    }
 
-                    let pixel_x = x as usize + px as usize;
+    fn sprite_pattern(&self, y: u8, tile: u8, attr: u8) -> (u8, u8) {
+        let height: u16 = if self.sprite_8x16 {
+            16
+        } else {
+            8
+        };
+        let flip_v = (attr & (1 << 7)) != 0;
+        let mut row = (self.scanline - y as i16) as u16;
+        if flip_v {
+            row = height - 1 - row;
+        }
 
-                    if (pixel_x <= 255) && pv > 0 &&
-                       (self.sprite_left_8px || (!self.sprite_left_8px && (pixel_x > 7))){
+        let addr = if self.sprite_8x16 {
+            // bit 0 of the tile picks the table, the bottom half is the next tile
+            let table = (tile as u16 & 1) * 0x1000;
+            let mut index = tile as u16 & 0xFE;
+            if row > 7 {
+                index += 1;
+            }
+            table + index * 16 + (row & 7)
+        } else {
+            let table = if self.sprite_table_high {
+                0x1000
+            } else {
+                0
+            };
+            table + tile as u16 * 16 + row
+        };
 
-                        let plt = self.palette[pal as usize + (pv - 1) as usize] as usize;
-                        let pixel = PALETTE[plt % 64];
-                        self.sprite_render[x as usize + px as usize] = (pixel, background);
+        (self.read_data(addr), self.read_data(addr + 8))
+    }
 
-                        if sprite == 0 && !self.sprite0_hit && (x as usize + px as usize) < 255 {
-                            self.sprite0_prerender[px]  = pv
-                        }
-                    }
-                }
+    fn draw_sprite_row(line: &mut [(u8, bool, bool); 256],
+                       x: u8, attr: u8, lo: u8, hi: u8, is_sprite0: bool)
+    {
+        let flip_h = (attr & (1 << 6)) != 0;
+        let background = (attr & 0x20) != 0;
+        let pal = 0x10 + (attr & 0b11) * 4;
+
+        for px in 0..8 {
+            let bit = if flip_h {
+                px
+            } else {
+                7 - px
+            };
+            let pv = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+            let pixel_x = x as usize + px as usize;
+
+            // lower OAM indexes are drawn first and take priority
+            if pixel_x > 255 || pv == 0 || line[pixel_x].0 != 0 {
+                continue;
             }
+            line[pixel_x] = (pal + pv, background, is_sprite0);
         }
     }
 
@@ -635,3 +910,13 @@ impl PPU {
     }
 
 }
+
+// $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_addr(addr: u16) -> usize {
+    let addr = (addr & 0x1F) as usize;
+    if addr & 0x13 == 0x10 {
+        addr - 0x10
+    } else {
+        addr
+    }
+}
@@ -0,0 +1,107 @@
+// Records the APU to .wav files, either the final mix or one file per
+// channel. It has its own band-limited buffers running at exactly the
+// nominal output rate, so the recording is sample accurate however the
+// audio device is being kept fed and even when there isn't one.
+use std::io;
+
+use audio::{FilterProfile, OutputFilter};
+use blip::{BlipBuf, Quality};
+use mixer::CHANNEL_NAMES;
+use wav::WavWriter;
+
+pub struct Recorder {
+    stems: bool,
+    // left and right of the mix, or every channel before the mixer
+    blips: Vec<BlipBuf>,
+    filters: Vec<OutputFilter>,
+    levels: Vec<f32>,
+    samples: Vec<Vec<f32>>,
+    // one for the mix, one per channel for stems
+    writers: Vec<WavWriter>,
+    pub paths: Vec<String>,
+    interleaved: Vec<f32>,
+}
+
+impl Recorder {
+    // stems go to path-pulse1.wav, path-pulse2.wav ...
+    pub fn new(path: &str, stems: bool, channels: usize, clock_rate: f64, sample_rate: f64,
+               quality: Quality, filter: FilterProfile) -> io::Result<Recorder> {
+        let (buffers, paths) = if stems {
+            let base = path.strip_suffix(".wav").unwrap_or(path);
+            let paths = CHANNEL_NAMES.iter()
+                .map(|name| format!("{}-{}.wav", base, name))
+                .collect();
+            (CHANNEL_NAMES.len(), paths)
+        } else {
+            (channels, vec![path.to_string()])
+        };
+
+        let mut writers = Vec::new();
+        for path in &paths {
+            let file_channels = if stems { 1 } else { channels as u16 };
+            writers.push(WavWriter::create(path, file_channels, sample_rate as u32)?);
+        }
+
+        Ok(Recorder {
+            stems,
+            blips: (0..buffers).map(|_| BlipBuf::new(clock_rate, sample_rate, quality)).collect(),
+            filters: (0..buffers).map(|_| OutputFilter::new(filter, sample_rate)).collect(),
+            levels: vec![0.0; buffers],
+            samples: vec![Vec::new(); buffers],
+            writers,
+            paths,
+            interleaved: Vec::new(),
+        })
+    }
+
+    // called every CPU cycle with each channel's level and the mixed output
+    pub fn add(&mut self, time: usize, outputs: &[f32; 6], mixed: [f32; 2]) {
+        for n in 0..self.blips.len() {
+            let level = if self.stems {
+                outputs[n]
+            } else {
+                mixed[n]
+            };
+            if level != self.levels[n] {
+                self.blips[n].add_delta(time, level - self.levels[n]);
+                self.levels[n] = level;
+            }
+        }
+    }
+
+    pub fn end_frame(&mut self, clocks: usize) -> io::Result<()> {
+        for n in 0..self.blips.len() {
+            self.blips[n].end_frame(clocks);
+            self.samples[n].clear();
+            self.blips[n].read_samples(&mut self.samples[n]);
+            for sample in self.samples[n].iter_mut() {
+                *sample = self.filters[n].process(*sample);
+            }
+        }
+
+        if self.stems {
+            for (writer, samples) in self.writers.iter_mut().zip(self.samples.iter()) {
+                writer.write(samples)?;
+            }
+        } else {
+            self.interleaved.clear();
+            for n in 0..self.samples[0].len() {
+                for samples in &self.samples {
+                    self.interleaved.push(samples[n]);
+                }
+            }
+            self.writers[0].write(&self.interleaved)?;
+        }
+        Ok(())
+    }
+
+    // clocks into the current frame, whatever's been added so far gets
+    // written out before the headers are
+    pub fn finish(mut self, clocks: usize) -> io::Result<()> {
+        self.end_frame(clocks)?;
+        for writer in self.writers {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
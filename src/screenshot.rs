@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+// 24 bit uncompressed BMP from 0xRRGGBB pixels
+pub fn save_bmp(path: &str, pixels: &[u32], w: usize, h: usize) -> io::Result<()> {
+    let row_size = (w * 3 + 3) & !3;
+    let image_size = row_size * h;
+    let file_size = 54 + image_size;
+
+    let mut data = Vec::with_capacity(file_size);
+    data.extend_from_slice(b"BM");
+    push_u32(&mut data, file_size as u32);
+    push_u32(&mut data, 0);
+    push_u32(&mut data, 54);
+
+    // BITMAPINFOHEADER
+    push_u32(&mut data, 40);
+    push_u32(&mut data, w as u32);
+    push_u32(&mut data, h as u32);
+    data.extend_from_slice(&[1, 0, 24, 0]);
+    push_u32(&mut data, 0);
+    push_u32(&mut data, image_size as u32);
+    push_u32(&mut data, 2835);
+    push_u32(&mut data, 2835);
+    push_u32(&mut data, 0);
+    push_u32(&mut data, 0);
+
+    // rows are stored bottom up
+    for row in (0..h).rev() {
+        for col in 0..w {
+            let pixel = pixels[row * w + col];
+            data.push(pixel as u8);
+            data.push((pixel >> 8) as u8);
+            data.push((pixel >> 16) as u8);
+        }
+        // padded to a multiple of 4 bytes
+        data.resize(data.len() + row_size - w * 3, 0);
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&data)
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+    data.push((value >> 16) as u8);
+    data.push((value >> 24) as u8);
+}
+
+// rom-0.bmp, rom-1.bmp ... in the current directory
+pub fn next_path(rompath: &str) -> String {
+    numbered_path(rompath, "bmp")
+}
+
+pub fn numbered_path(rompath: &str, extension: &str) -> String {
+    let stem = Path::new(rompath).file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or(String::from("screenshot"));
+    let mut n = 0;
+    loop {
+        let path = format!("{}-{}.{}", stem, n, extension);
+        if !Path::new(&path).exists() {
+            return path;
+        }
+        n += 1;
+    }
+}
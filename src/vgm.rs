@@ -0,0 +1,191 @@
+// VGM 1.71 logging of the APU. Register writes go in as NES APU commands
+// with waits between them at the VGM's 44.1kHz, and DMC sample bytes go in
+// as RAM data blocks so the player has its own copy of sample memory.
+use std::fs::File;
+use std::io::{self, Write};
+
+const VGM_RATE: f64 = 44100.0;
+const HEADER_LEN: usize = 0x100;
+// NES APU RAM write data block
+const DPCM_BLOCK: u8 = 0xC2;
+
+pub struct VgmLogger {
+    pub path: String,
+    file: File,
+    clock_rate: f64,
+    // the CPU cycle logging started on
+    start_cycle: u64,
+    data: Vec<u8>,
+    samples: u64,
+    // offset into the data and the sample count at the loop point
+    loop_point: Option<(usize, u64)>,
+    // what the player will have at $8000-$FFFF
+    sample_memory: Vec<Option<u8>>,
+    // run of sample bytes not in the log yet
+    pending: Option<(u16, Vec<u8>)>,
+}
+
+impl VgmLogger {
+    pub fn new(path: &str, clock_rate: f64, cycle: u64) -> io::Result<VgmLogger> {
+        Ok(VgmLogger {
+            path: path.to_string(),
+            file: File::create(path)?,
+            clock_rate,
+            start_cycle: cycle,
+            data: Vec::new(),
+            samples: 0,
+            loop_point: None,
+            sample_memory: vec![None; 0x8000],
+            pending: None,
+        })
+    }
+
+    // $4000-$4017
+    pub fn write(&mut self, cycle: u64, addr: u16, value: u8) {
+        self.wait_until(cycle);
+        self.data.push(0xB4);
+        self.data.push((addr - 0x4000) as u8);
+        self.data.push(value);
+    }
+
+    // only bytes the player doesn't already have are logged
+    pub fn dmc_byte(&mut self, addr: u16, value: u8) {
+        let index = (addr & 0x7FFF) as usize;
+        if self.sample_memory[index] == Some(value) {
+            return;
+        }
+        self.sample_memory[index] = Some(value);
+
+        let follows = match self.pending {
+            Some((start, ref bytes)) => start as usize + bytes.len() == addr as usize,
+            None => false,
+        };
+        if !follows {
+            self.flush_block();
+            self.pending = Some((addr, Vec::new()));
+        }
+        if let Some((_, ref mut bytes)) = self.pending {
+            bytes.push(value);
+        }
+    }
+
+    pub fn set_loop(&mut self, cycle: u64) {
+        self.wait_until(cycle);
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    fn wait_until(&mut self, cycle: u64) {
+        self.flush_block();
+        let elapsed = (cycle - self.start_cycle) as f64;
+        let target = (elapsed * VGM_RATE / self.clock_rate) as u64;
+        while self.samples < target {
+            let wait = if target - self.samples > 0xFFFF {
+                0xFFFF
+            } else {
+                target - self.samples
+            };
+            match wait {
+                735 => self.data.push(0x62),
+                882 => self.data.push(0x63),
+                1..=16 => self.data.push(0x70 + (wait - 1) as u8),
+                _ => {
+                    self.data.push(0x61);
+                    push_u16(&mut self.data, wait as u16);
+                }
+            }
+            self.samples += wait;
+        }
+    }
+
+    fn flush_block(&mut self) {
+        if let Some((start, bytes)) = self.pending.take() {
+            self.data.extend_from_slice(&[0x67, 0x66, DPCM_BLOCK]);
+            // the size includes the start address
+            push_u32(&mut self.data, bytes.len() as u32 + 2);
+            push_u16(&mut self.data, start);
+            self.data.extend_from_slice(&bytes);
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush_block();
+        self.data.push(0x66);
+
+        let mut header = vec![0; HEADER_LEN];
+        header[0..4].copy_from_slice(b"Vgm ");
+        set_u32(&mut header, 0x04, (HEADER_LEN + self.data.len() - 4) as u32);
+        set_u32(&mut header, 0x08, 0x171);
+        set_u32(&mut header, 0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            // relative to where each field is
+            set_u32(&mut header, 0x1C, (HEADER_LEN + offset - 0x1C) as u32);
+            set_u32(&mut header, 0x20, (self.samples - samples) as u32);
+        }
+        set_u32(&mut header, 0x24, 60);
+        set_u32(&mut header, 0x34, (HEADER_LEN - 0x34) as u32);
+        set_u32(&mut header, 0x84, self.clock_rate as u32);
+
+        self.file.write_all(&header)?;
+        self.file.write_all(&self.data)
+    }
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+    data.push((value >> 16) as u8);
+    data.push((value >> 24) as u8);
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset] = value as u8;
+    data[offset + 1] = (value >> 8) as u8;
+    data[offset + 2] = (value >> 16) as u8;
+    data[offset + 3] = (value >> 24) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // at a clock rate of 44.1kHz every cycle is one VGM sample
+    fn logger(name: &str) -> VgmLogger {
+        let path = ::std::env::temp_dir().join(name);
+        VgmLogger::new(path.to_str().unwrap(), VGM_RATE, 0).unwrap()
+    }
+
+    #[test]
+    fn waits() {
+        let mut vgm = logger("vgm_waits.vgm");
+        vgm.write(735, 0x4015, 0x0F);
+        vgm.write(735 + 882, 0x4015, 0x0F);
+        vgm.write(735 + 882 + 16, 0x4015, 0x0F);
+        vgm.write(735 + 882 + 16 + 1000, 0x4015, 0x0F);
+        vgm.write(735 + 882 + 16 + 1000 + 0x10000, 0x4015, 0x0F);
+        assert_eq!(vgm.data, [0x62, 0xB4, 0x15, 0x0F,
+                              0x63, 0xB4, 0x15, 0x0F,
+                              0x7F, 0xB4, 0x15, 0x0F,
+                              0x61, 0xE8, 0x03, 0xB4, 0x15, 0x0F,
+                              0x61, 0xFF, 0xFF, 0x70, 0xB4, 0x15, 0x0F]);
+        assert_eq!(vgm.samples, 735 + 882 + 16 + 1000 + 0x10000);
+    }
+
+    #[test]
+    fn sample_data_blocks() {
+        let mut vgm = logger("vgm_blocks.vgm");
+        vgm.dmc_byte(0xC000, 0x12);
+        vgm.dmc_byte(0xC001, 0x34);
+        // already in the player's memory
+        vgm.dmc_byte(0xC000, 0x12);
+        vgm.dmc_byte(0xC100, 0x56);
+        vgm.write(0, 0x4010, 0x0F);
+        assert_eq!(vgm.data, [0x67, 0x66, DPCM_BLOCK, 0x04, 0x00, 0x00, 0x00, 0x00, 0xC0, 0x12, 0x34,
+                              0x67, 0x66, DPCM_BLOCK, 0x03, 0x00, 0x00, 0x00, 0x00, 0xC1, 0x56,
+                              0xB4, 0x10, 0x0F]);
+    }
+}
@@ -0,0 +1,298 @@
+// Debug views of the PPU's memory: nametables, pattern tables, OAM and
+// palette RAM. Everything is drawn straight from VRAM/CHR/OAM so the views
+// show what the game has set up, not what ended up on screen.
+// The event view draws the PPU's event log instead.
+use events::{TIMELINE_WIDTH, TIMELINE_HEIGHT};
+use palette::Palette;
+use ppu::PPU;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    Nametables,
+    PatternTables,
+    Sprites,
+    Palette,
+    Events,
+}
+
+impl View {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            View::Nametables => "nametables",
+            View::PatternTables => "pattern tables",
+            View::Sprites => "sprites",
+            View::Palette => "palette",
+            View::Events => "events",
+        }
+    }
+
+    pub fn next(&self) -> View {
+        match *self {
+            View::Nametables => View::PatternTables,
+            View::PatternTables => View::Sprites,
+            View::Sprites => View::Palette,
+            View::Palette => View::Events,
+            View::Events => View::Nametables,
+        }
+    }
+}
+
+// the sprite view is 8x8 cells big enough for 8x16 sprites
+const SPRITE_CELL_W: usize = 16;
+const SPRITE_CELL_H: usize = 24;
+const SWATCH: usize = 16;
+
+pub const MAX_WIDTH: usize = 512;
+pub const MAX_HEIGHT: usize = 480;
+
+pub struct Viewer {
+    pub view: View,
+    // 0-3 background, 4-7 sprite palettes for the pattern tables
+    pub palette_index: usize,
+    pub output: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Viewer {
+    pub fn new() -> Viewer {
+        Viewer {
+            view: View::Nametables,
+            palette_index: 0,
+            output: vec![0; MAX_WIDTH * MAX_HEIGHT],
+            width: MAX_WIDTH,
+            height: MAX_HEIGHT,
+        }
+    }
+
+    // how much the view is blown up in its window
+    pub fn scale(&self) -> usize {
+        match self.view {
+            View::Nametables => 1,
+            _ => 2,
+        }
+    }
+
+    pub fn render(&mut self, ppu: &PPU, palette: &Palette) {
+        let (width, height) = match self.view {
+            View::Nametables => (512, 480),
+            View::PatternTables => (256, 128),
+            View::Sprites => (SPRITE_CELL_W * 8, SPRITE_CELL_H * 8),
+            View::Palette => (SWATCH * 16, SWATCH * 2),
+            View::Events => (TIMELINE_WIDTH, TIMELINE_HEIGHT),
+        };
+        self.width = width;
+        self.height = height;
+        self.output.clear();
+        self.output.resize(width * height, 0);
+
+        match self.view {
+            View::Nametables => self.render_nametables(ppu, palette),
+            View::PatternTables => self.render_pattern_tables(ppu, palette),
+            View::Sprites => self.render_sprites(ppu, palette),
+            View::Palette => self.render_palette(ppu, palette),
+            View::Events => ppu.events.render(&mut self.output, self.width),
+        }
+    }
+
+    // what is under x, y in view coordinates
+    pub fn describe(&self, ppu: &PPU, x: usize, y: usize) -> Option<String> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        match self.view {
+            View::Nametables => {
+                let (addr, column, row) = nametable_tile(x, y);
+                let tile = ppu.peek(addr);
+                let attr_addr = attribute_addr(addr);
+                let attr = attribute(ppu.peek(attr_addr), column, row);
+                Some(format!("${:04X} tile ${:02X} attribute ${:04X} palette {}",
+                             addr, tile, attr_addr, attr))
+            }
+            View::PatternTables => {
+                let table = x / 128;
+                let tile = (y / 8) * 16 + (x % 128) / 8;
+                let addr = table * 0x1000 + tile * 16;
+                Some(format!("${:04X} tile ${:02X}", addr, tile))
+            }
+            View::Sprites => {
+                let n = (y / SPRITE_CELL_H) * 8 + x / SPRITE_CELL_W;
+                let oam = ppu.oam();
+                let (sy, tile, attr, sx) = (oam[n * 4], oam[n * 4 + 1], oam[n * 4 + 2], oam[n * 4 + 3]);
+                Some(format!("sprite {} x {} y {} tile ${:02X} palette {}{}{}{}",
+                             n, sx, sy, tile, (attr & 3) + 4,
+                             if attr & 0x20 != 0 { " behind" } else { "" },
+                             if attr & 0x40 != 0 { " flip-h" } else { "" },
+                             if attr & 0x80 != 0 { " flip-v" } else { "" }))
+            }
+            View::Palette => {
+                let entry = (y / SWATCH) * 16 + x / SWATCH;
+                let addr = 0x3F00 + entry as u16;
+                Some(format!("${:04X} = ${:02X}", addr, ppu.peek(addr) & 0x3F))
+            }
+            View::Events => {
+                let events = ppu.events.describe(x, y);
+                if events.is_empty() {
+                    Some(format!("SL {} dot {}", if y == 261 { -1 } else { y as isize }, x))
+                } else {
+                    Some(events.join(", "))
+                }
+            }
+        }
+    }
+
+    fn render_nametables(&mut self, ppu: &PPU, palette: &Palette) {
+        let bg_table = ppu.bg_table();
+        for ty in 0..60 {
+            for tx in 0..64 {
+                let (addr, column, row) = nametable_tile(tx * 8, ty * 8);
+                let tile = ppu.peek(addr) as u16;
+                let attr = attribute(ppu.peek(attribute_addr(addr)), column, row);
+                let colors = palette_colors(ppu, palette, attr);
+                self.draw_tile(ppu, bg_table + tile * 16, tx * 8, ty * 8, &colors);
+            }
+        }
+
+        // the scroll rectangle wraps around the edges like the PPU does
+        let (sx, sy) = ppu.scroll();
+        for i in 0..256 {
+            self.invert((sx + i) % 512, sy % 480);
+            self.invert((sx + i) % 512, (sy + 239) % 480);
+        }
+        for i in 1..239 {
+            self.invert(sx % 512, (sy + i) % 480);
+            self.invert((sx + 255) % 512, (sy + i) % 480);
+        }
+    }
+
+    fn render_pattern_tables(&mut self, ppu: &PPU, palette: &Palette) {
+        let colors = palette_colors(ppu, palette, self.palette_index);
+        for table in 0..2 {
+            for tile in 0..256 {
+                let x = table * 128 + (tile % 16) * 8;
+                let y = (tile / 16) * 8;
+                let addr = (table * 0x1000 + tile * 16) as u16;
+                self.draw_tile(ppu, addr, x, y, &colors);
+            }
+        }
+    }
+
+    fn render_sprites(&mut self, ppu: &PPU, palette: &Palette) {
+        for pixel in self.output.iter_mut() {
+            *pixel = 0x202020;
+        }
+        let oam = ppu.oam();
+        for n in 0..64 {
+            let tile = oam[n * 4 + 1] as u16;
+            let attr = oam[n * 4 + 2];
+            let colors = palette_colors(ppu, palette, (attr & 3) as usize + 4);
+            let x = (n % 8) * SPRITE_CELL_W + 4;
+            let y = (n / 8) * SPRITE_CELL_H + 4;
+            let flip_h = attr & 0x40 != 0;
+            let flip_v = attr & 0x80 != 0;
+
+            if ppu.sprite_8x16() {
+                let table = (tile & 1) * 0x1000;
+                let (top, bottom) = if flip_v {
+                    (tile & 0xFE | 1, tile & 0xFE)
+                } else {
+                    (tile & 0xFE, tile & 0xFE | 1)
+                };
+                self.draw_sprite_tile(ppu, table + top * 16, x, y, &colors, (flip_h, flip_v));
+                self.draw_sprite_tile(ppu, table + bottom * 16, x, y + 8, &colors, (flip_h, flip_v));
+            } else {
+                let addr = ppu.sprite_table() + tile * 16;
+                self.draw_sprite_tile(ppu, addr, x, y, &colors, (flip_h, flip_v));
+            }
+        }
+    }
+
+    fn render_palette(&mut self, ppu: &PPU, palette: &Palette) {
+        for entry in 0..32 {
+            let color = palette.rgb((ppu.peek(0x3F00 + entry as u16) & 0x3F) as u16);
+            let x = (entry % 16) * SWATCH;
+            let y = (entry / 16) * SWATCH;
+            for row in 0..SWATCH {
+                for col in 0..SWATCH {
+                    self.output[(y + row) * self.width + x + col] = color;
+                }
+            }
+        }
+    }
+
+    fn draw_tile(&mut self, ppu: &PPU, addr: u16, x: usize, y: usize, colors: &[u32; 4]) {
+        for row in 0..8 {
+            let lo = ppu.peek(addr + row as u16);
+            let hi = ppu.peek(addr + row as u16 + 8);
+            for col in 0..8 {
+                let bit = 7 - col;
+                let value = (lo >> bit & 1) | (hi >> bit & 1) << 1;
+                self.output[(y + row) * self.width + x + col] = colors[value as usize];
+            }
+        }
+    }
+
+    // like draw_tile but transparent pixels are left alone
+    fn draw_sprite_tile(&mut self, ppu: &PPU, addr: u16, x: usize, y: usize,
+                        colors: &[u32; 4], (flip_h, flip_v): (bool, bool)) {
+        for row in 0..8 {
+            let src_row = if flip_v {
+                7 - row
+            } else {
+                row
+            } as u16;
+            let lo = ppu.peek(addr + src_row);
+            let hi = ppu.peek(addr + src_row + 8);
+            for col in 0..8 {
+                let bit = if flip_h {
+                    col
+                } else {
+                    7 - col
+                };
+                let value = (lo >> bit & 1) | (hi >> bit & 1) << 1;
+                if value != 0 {
+                    self.output[(y + row) * self.width + x + col] = colors[value as usize];
+                }
+            }
+        }
+    }
+
+    fn invert(&mut self, x: usize, y: usize) {
+        let pixel = &mut self.output[y * self.width + x];
+        *pixel ^= 0xFFFFFF;
+    }
+}
+
+// nametable address and tile column/row in the 64x60 tile area
+fn nametable_tile(x: usize, y: usize) -> (u16, usize, usize) {
+    let column = x / 8;
+    let row = y / 8;
+    let nametable = (row / 30) * 2 + column / 32;
+    let addr = 0x2000 + nametable * 0x400 + (row % 30) * 32 + column % 32;
+    (addr as u16, column % 32, row % 30)
+}
+
+fn attribute_addr(tile_addr: u16) -> u16 {
+    let column = tile_addr & 0x1F;
+    let row = (tile_addr >> 5) & 0x1F;
+    (tile_addr & 0x2C00) | 0x3C0 | ((row / 4) << 3) | (column / 4)
+}
+
+fn attribute(value: u8, column: usize, row: usize) -> usize {
+    let shift = (row & 2) * 2 + (column & 2);
+    (value as usize >> shift) & 3
+}
+
+// one of the 8 palettes, colour 0 is always the backdrop
+fn palette_colors(ppu: &PPU, palette: &Palette, index: usize) -> [u32; 4] {
+    let mut colors = [0; 4];
+    for (i, color) in colors.iter_mut().enumerate() {
+        let addr = if i == 0 {
+            0x3F00
+        } else {
+            0x3F00 + (index * 4 + i) as u16
+        };
+        *color = palette.rgb((ppu.peek(addr) & 0x3F) as u16);
+    }
+    colors
+}
@@ -0,0 +1,294 @@
+// Konami's VRC6 sound: two pulse channels with 8 duty settings and a
+// sawtooth. Mapper 24 has the registers at $9000-$9003, $A000-$A002 and
+// $B000-$B002, mapper 26 is wired with A0 and A1 swapped.
+use expansion::ExpansionAudio;
+
+// The chip's outputs are summed linearly. A VRC6 pulse at full volume is
+// about as loud as an APU pulse at full volume.
+const LEVEL: f32 = 95.88 / (8128.0 / 15.0 + 100.0) / 15.0;
+
+pub struct Vrc6 {
+    swap_lines: bool,
+    // $9003, the period is divided by 16 or 256 and everything can be halted
+    halt: bool,
+    shift: usize,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+}
+
+impl Vrc6 {
+    pub fn new(swap_lines: bool) -> Vrc6 {
+        Vrc6 {
+            swap_lines,
+            halt: false,
+            shift: 0,
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6 {
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        let addr = if self.swap_lines {
+            (addr & 0xF000) | (addr & 1) << 1 | (addr & 2) >> 1
+        } else {
+            addr & 0xF003
+        };
+        match addr {
+            0x9000..=0x9002 => self.pulse1.write(addr & 3, value),
+            0x9003 => {
+                self.halt = value & 1 != 0;
+                self.shift = if value & 4 != 0 {
+                    8
+                } else if value & 2 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write(addr & 3, value),
+            0xB000..=0xB002 => self.saw.write(addr & 3, value),
+            _ => {}
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        sum as f32 * LEVEL
+    }
+}
+
+struct Vrc6Pulse {
+    enabled: bool,
+    // ignores the duty and outputs the volume all the time
+    constant: bool,
+    duty: u8,
+    volume: u8,
+    period: usize,
+    period_counter: usize,
+    // counts down 15 to 0, high while it's at or below the duty
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            enabled: false,
+            constant: false,
+            duty: 0,
+            volume: 0,
+            period: 0,
+            period_counter: 1,
+            step: 15,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.constant = value & 0x80 != 0;
+                self.duty = (value >> 4) & 7;
+                self.volume = value & 0x0F;
+            }
+            1 => self.period = (self.period & 0xF00) | value as usize,
+            _ => {
+                self.period = (self.period & 0xFF) | (value as usize & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                // disabling resets the duty cycle
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: usize) {
+        if !self.enabled {
+            return;
+        }
+        self.period_counter -= 1;
+        if self.period_counter == 0 {
+            self.period_counter = (self.period >> shift) + 1;
+            self.step = if self.step == 0 { 15 } else { self.step - 1 };
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Vrc6Saw {
+    enabled: bool,
+    // added to the accumulator every other step
+    rate: u8,
+    period: usize,
+    period_counter: usize,
+    // 0 to 13, the accumulator is cleared on the 14th
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw {
+            enabled: false,
+            rate: 0,
+            period: 0,
+            period_counter: 1,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0xF00) | value as usize,
+            _ => {
+                self.period = (self.period & 0xFF) | (value as usize & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: usize) {
+        if !self.enabled {
+            return;
+        }
+        self.period_counter -= 1;
+        if self.period_counter == 0 {
+            self.period_counter = (self.period >> shift) + 1;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step & 1 == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        }
+    }
+
+    // the top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mixer::{Mixer, CHANNEL_NAMES, EXPANSION, PULSE1};
+
+    fn clock(chip: &mut Vrc6, cycles: usize) {
+        for _ in 0..cycles {
+            chip.clock();
+        }
+    }
+
+    #[test]
+    fn pulse_duty() {
+        let mut chip = Vrc6::new(false);
+        // duty 3 is 4/16 high, period 0 steps every cycle
+        chip.write(0x9000, 0x3F);
+        chip.write(0x9001, 0x00);
+        chip.write(0x9002, 0x80);
+        let mut high = 0;
+        for _ in 0..16 {
+            chip.clock();
+            if chip.output() > 0.0 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 4);
+
+        chip.write(0x9002, 0x00);
+        assert_eq!(chip.output(), 0.0);
+    }
+
+    #[test]
+    fn saw_ramps_and_resets() {
+        let mut chip = Vrc6::new(false);
+        chip.write(0xB000, 42);
+        chip.write(0xB002, 0x80);
+        // six additions by step 12, then cleared on step 14
+        clock(&mut chip, 12);
+        assert_eq!(chip.saw.output(), (42 * 6) >> 3);
+        clock(&mut chip, 2);
+        assert_eq!(chip.saw.output(), 0);
+    }
+
+    #[test]
+    fn swapped_lines() {
+        // $9001 and $9002 trade places on mapper 26
+        let mut chip = Vrc6::new(true);
+        chip.write(0x9000, 0x8F);
+        chip.write(0x9001, 0x80);
+        assert!(chip.pulse1.enabled);
+        chip.write(0xB001, 0x80);
+        assert!(chip.saw.enabled);
+    }
+
+    #[test]
+    fn halt() {
+        let mut chip = Vrc6::new(false);
+        chip.write(0xB000, 8);
+        chip.write(0xB002, 0x80);
+        chip.write(0x9003, 0x01);
+        clock(&mut chip, 10);
+        assert_eq!(chip.saw.accumulator, 0);
+    }
+
+    #[test]
+    fn level_matches_the_apu_pulse() {
+        let mut chip = Vrc6::new(false);
+        chip.write(0x9000, 0x8F);
+        chip.write(0x9002, 0x80);
+        let apu_pulse = 95.88 / (8128.0 / 15.0 + 100.0);
+        assert!((chip.output() - apu_pulse).abs() < 0.0001);
+        // everything at full is well clear of clipping
+        chip.write(0xA000, 0x8F);
+        chip.write(0xA002, 0x80);
+        chip.saw.accumulator = 0xFF;
+        assert!(chip.output() < 0.7);
+    }
+
+    #[test]
+    fn goes_through_the_expansion_channel() {
+        let mut chip = Vrc6::new(false);
+        chip.write(0x9000, 0x8F);
+        chip.write(0x9002, 0x80);
+        let mut outputs = [0.0; 6];
+        outputs[PULSE1] = 0.05;
+        outputs[EXPANSION] = chip.output();
+        assert_eq!(CHANNEL_NAMES[EXPANSION], "expansion");
+
+        let mut mixer = Mixer::new();
+        let (full, _) = mixer.mix(&outputs);
+        assert!((full - 0.05 - chip.output()).abs() < 0.0001);
+
+        mixer.toggle(EXPANSION);
+        let (muted, _) = mixer.mix(&outputs);
+        assert!((muted - 0.05).abs() < 0.0001);
+    }
+}
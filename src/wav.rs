@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+// 16 bit PCM, the sizes in the header are filled in by finish
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, channels: u16, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            channels,
+            sample_rate,
+            data_len: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = self.channels as u32 * 2;
+        let mut data = Vec::with_capacity(44);
+        data.extend_from_slice(b"RIFF");
+        push_u32(&mut data, 36 + self.data_len);
+        data.extend_from_slice(b"WAVE");
+
+        data.extend_from_slice(b"fmt ");
+        push_u32(&mut data, 16);
+        push_u16(&mut data, 1); // PCM
+        push_u16(&mut data, self.channels);
+        push_u32(&mut data, self.sample_rate);
+        push_u32(&mut data, self.sample_rate * block_align);
+        push_u16(&mut data, block_align as u16);
+        push_u16(&mut data, 16);
+
+        data.extend_from_slice(b"data");
+        push_u32(&mut data, self.data_len);
+        self.file.write_all(&data)
+    }
+
+    // interleaved when there is more than one channel
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            let value = sample.clamp(-1.0, 1.0);
+            let value = (value * 32767.0) as i16 as u16;
+            push_u16(&mut data, value);
+        }
+        self.data_len += data.len() as u32;
+        self.file.write_all(&data)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+    data.push((value >> 16) as u8);
+    data.push((value >> 24) as u8);
+}
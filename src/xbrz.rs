@@ -0,0 +1,376 @@
+// xBRZ, Zenju's variant of Hyllian's xBR, for 2x to 6x.
+// Every corner where four source pixels meet is judged once from the 4x4
+// pixels around it: if the gradient along one diagonal is clearly lower
+// than along the other, the two pixels on the other diagonal get that
+// corner blended. Then each pixel's block is filled with its colour and
+// the blended corners are drawn in as a round corner, a 45 degree line or
+// a shallow or steep line, depending on the pixels further out.
+use filters::pixel;
+
+const EQUAL_COLOR_TOLERANCE: f64 = 30.0;
+const CENTER_DIRECTION_BIAS: f64 = 4.0;
+const DOMINANT_DIRECTION_THRESHOLD: f64 = 3.6;
+const STEEP_DIRECTION_THRESHOLD: f64 = 2.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Blend {
+    None,
+    Normal,
+    // the edge is clear enough to always draw a line, never a corner
+    Dominant,
+}
+
+// Which corners of a pixel get blended, clockwise from the top left so
+// rotating the pixel is rotating the array.
+type Corners = [Blend; 4];
+const TOP_LEFT: usize = 0;
+const TOP_RIGHT: usize = 1;
+const BOTTOM_RIGHT: usize = 2;
+const BOTTOM_LEFT: usize = 3;
+
+// Distance in YCbCr with BT.2020 weights. The reference implementation
+// reads this from a table at half the precision.
+fn dist(a: u32, b: u32) -> f64 {
+    let diff = |shift: u32| ((a >> shift) & 0xFF) as f64 - ((b >> shift) & 0xFF) as f64;
+    let (r, g, b) = (diff(16), diff(8), diff(0));
+
+    const K_B: f64 = 0.0593;
+    const K_R: f64 = 0.2627;
+    const K_G: f64 = 1.0 - K_B - K_R;
+    let scale_b = 0.5 / (1.0 - K_B);
+    let scale_r = 0.5 / (1.0 - K_R);
+
+    let y = K_R * r + K_G * g + K_B * b;
+    let c_b = scale_b * (b - y);
+    let c_r = scale_r * (r - y);
+    (y * y + c_b * c_b + c_r * c_r).sqrt()
+}
+
+fn eq(a: u32, b: u32) -> bool {
+    dist(a, b) < EQUAL_COLOR_TOLERANCE
+}
+
+// Blends for the corner between f, g, j and k, as (f, g, j, k), from
+//   a b c d
+//   e f g h
+//   i j k l
+//   m n o p
+fn corner(k4: &[u32; 16]) -> (Blend, Blend, Blend, Blend) {
+    let [_, b, c, _, e, f, g, h, i, j, k, l, _, n, o, _] = *k4;
+    let mut result = (Blend::None, Blend::None, Blend::None, Blend::None);
+    if (f == g && j == k) || (f == j && g == k) {
+        return result;
+    }
+
+    let jg = dist(i, f) + dist(f, c) + dist(n, k) + dist(k, h) +
+             CENTER_DIRECTION_BIAS * dist(j, g);
+    let fk = dist(e, j) + dist(j, o) + dist(b, g) + dist(g, l) +
+             CENTER_DIRECTION_BIAS * dist(f, k);
+
+    if jg < fk {
+        let blend = if DOMINANT_DIRECTION_THRESHOLD * jg < fk {
+            Blend::Dominant
+        } else {
+            Blend::Normal
+        };
+        if f != g && f != j {
+            result.0 = blend;
+        }
+        if k != j && k != g {
+            result.3 = blend;
+        }
+    } else if fk < jg {
+        let blend = if DOMINANT_DIRECTION_THRESHOLD * fk < jg {
+            Blend::Dominant
+        } else {
+            Blend::Normal
+        };
+        if j != f && j != k {
+            result.2 = blend;
+        }
+        if g != f && g != k {
+            result.1 = blend;
+        }
+    }
+    result
+}
+
+// out = (color * m + out * (n - m)) / n per channel
+fn alpha_grad(out: &mut u32, color: u32, m: u32, n: u32) {
+    let channel = |shift: u32| {
+        let front = (color >> shift) & 0xFF;
+        let back = (*out >> shift) & 0xFF;
+        ((front * m + back * (n - m)) / n) << shift
+    };
+    *out = channel(16) | channel(8) | channel(0);
+}
+
+// (row, column, m, n) blended into the bottom right of an n x n block,
+// m == n is the colour drawn as is. Steep lines are the shallow ones
+// mirrored along the diagonal.
+type Shape = &'static [(usize, usize, u32, u32)];
+
+struct Shapes {
+    shallow: Shape,
+    steep_and_shallow: Shape,
+    diagonal: Shape,
+    corner: Shape,
+}
+
+const SHAPES: [Shapes; 5] = [
+    Shapes {
+        shallow: &[(1, 0, 1, 4), (1, 1, 3, 4)],
+        steep_and_shallow: &[(1, 0, 1, 4), (0, 1, 1, 4), (1, 1, 5, 6)],
+        diagonal: &[(1, 1, 1, 2)],
+        corner: &[(1, 1, 21, 100)],
+    },
+    Shapes {
+        shallow: &[(2, 0, 1, 4), (1, 2, 1, 4), (2, 1, 3, 4), (2, 2, 1, 1)],
+        steep_and_shallow: &[(2, 0, 1, 4), (0, 2, 1, 4), (2, 1, 3, 4), (1, 2, 3, 4),
+                             (2, 2, 1, 1)],
+        diagonal: &[(1, 2, 1, 8), (2, 1, 1, 8), (2, 2, 7, 8)],
+        corner: &[(2, 2, 45, 100)],
+    },
+    Shapes {
+        shallow: &[(3, 0, 1, 4), (2, 2, 1, 4), (3, 1, 3, 4), (2, 3, 3, 4), (3, 2, 1, 1),
+                   (3, 3, 1, 1)],
+        steep_and_shallow: &[(3, 1, 3, 4), (1, 3, 3, 4), (3, 0, 1, 4), (0, 3, 1, 4),
+                             (2, 2, 1, 3), (3, 3, 1, 1), (3, 2, 1, 1), (2, 3, 1, 1)],
+        diagonal: &[(3, 2, 1, 2), (2, 3, 1, 2), (3, 3, 1, 1)],
+        corner: &[(3, 3, 68, 100), (3, 2, 9, 100), (2, 3, 9, 100)],
+    },
+    Shapes {
+        shallow: &[(4, 0, 1, 4), (3, 2, 1, 4), (2, 4, 1, 4), (4, 1, 3, 4), (3, 3, 3, 4),
+                   (4, 2, 1, 1), (4, 3, 1, 1), (4, 4, 1, 1), (3, 4, 1, 1)],
+        steep_and_shallow: &[(0, 4, 1, 4), (2, 3, 1, 4), (1, 4, 3, 4), (4, 0, 1, 4),
+                             (3, 2, 1, 4), (4, 1, 3, 4), (3, 3, 2, 3), (2, 4, 1, 1),
+                             (3, 4, 1, 1), (4, 4, 1, 1), (4, 2, 1, 1), (4, 3, 1, 1)],
+        diagonal: &[(4, 2, 1, 8), (3, 3, 1, 8), (2, 4, 1, 8), (4, 3, 7, 8), (3, 4, 7, 8),
+                    (4, 4, 1, 1)],
+        corner: &[(4, 4, 86, 100), (4, 3, 23, 100), (3, 4, 23, 100)],
+    },
+    Shapes {
+        shallow: &[(5, 0, 1, 4), (4, 2, 1, 4), (3, 4, 1, 4), (5, 1, 3, 4), (4, 3, 3, 4),
+                   (3, 5, 3, 4), (5, 2, 1, 1), (5, 3, 1, 1), (5, 4, 1, 1), (5, 5, 1, 1),
+                   (4, 4, 1, 1), (4, 5, 1, 1)],
+        steep_and_shallow: &[(0, 5, 1, 4), (2, 4, 1, 4), (1, 5, 3, 4), (3, 4, 3, 4),
+                             (5, 0, 1, 4), (4, 2, 1, 4), (5, 1, 3, 4), (4, 3, 3, 4),
+                             (2, 5, 1, 1), (3, 5, 1, 1), (4, 5, 1, 1), (5, 5, 1, 1),
+                             (4, 4, 1, 1), (5, 4, 1, 1), (5, 2, 1, 1), (5, 3, 1, 1)],
+        diagonal: &[(5, 3, 1, 2), (4, 4, 1, 2), (3, 5, 1, 2), (4, 5, 1, 1), (5, 5, 1, 1),
+                    (5, 4, 1, 1)],
+        corner: &[(5, 5, 97, 100), (4, 5, 42, 100), (5, 4, 42, 100), (5, 3, 6, 100),
+                  (3, 5, 6, 100)],
+    },
+];
+
+// The n x n output block of one pixel, turned a quarter clockwise per
+// rotation so the corner being drawn is always the bottom right one.
+struct Block<'a> {
+    dst: &'a mut [u32],
+    dw: usize,
+    pos: usize,
+    n: usize,
+    rotation: usize,
+}
+
+impl<'a> Block<'a> {
+    fn draw(&mut self, shape: Shape, mirror: bool, color: u32) {
+        for &(row, col, m, n) in shape.iter() {
+            let (mut row, mut col) = if mirror { (col, row) } else { (row, col) };
+            for _ in 0..self.rotation {
+                let turned = (self.n - 1 - col, row);
+                row = turned.0;
+                col = turned.1;
+            }
+            let out = &mut self.dst[self.pos + row * self.dw + col];
+            if m == n {
+                *out = color;
+            } else {
+                alpha_grad(out, color, m, n);
+            }
+        }
+    }
+}
+
+// Draws the bottom right corner of the pixel in the middle of
+//   a b c
+//   d e f
+//   g h i
+fn blend_pixel(k3: &[u32; 9], corners: &Corners, shapes: &Shapes, out: &mut Block) {
+    if corners[BOTTOM_RIGHT] == Blend::None {
+        return;
+    }
+    let [_, b, c, d, e, f, g, h, i] = *k3;
+
+    let line = if corners[BOTTOM_RIGHT] == Blend::Dominant {
+        true
+    } else if corners[TOP_RIGHT] != Blend::None && !eq(e, g) {
+        // another blended corner next to this one is a lone pixel, unless
+        // they make a right angle
+        false
+    } else if corners[BOTTOM_LEFT] != Blend::None && !eq(e, c) {
+        false
+    } else {
+        // L shapes only get a corner
+        !(!eq(e, i) && eq(g, h) && eq(h, i) && eq(i, f) && eq(f, c))
+    };
+
+    // blend towards whichever neighbour is closer
+    let color = if dist(e, f) <= dist(e, h) { f } else { h };
+
+    if line {
+        let fg = dist(f, g);
+        let hc = dist(h, c);
+        let shallow = STEEP_DIRECTION_THRESHOLD * fg <= hc && e != g && d != g;
+        let steep = STEEP_DIRECTION_THRESHOLD * hc <= fg && e != c && b != c;
+        match (shallow, steep) {
+            (true, true) => out.draw(shapes.steep_and_shallow, false, color),
+            (true, false) => out.draw(shapes.shallow, false, color),
+            (false, true) => out.draw(shapes.shallow, true, color),
+            (false, false) => out.draw(shapes.diagonal, false, color),
+        }
+    } else {
+        out.draw(shapes.corner, false, color);
+    }
+}
+
+// a quarter turn clockwise, what was at the top right is now at the
+// bottom right
+fn rotate(k3: &[u32; 9]) -> [u32; 9] {
+    [k3[6], k3[3], k3[0], k3[7], k3[4], k3[1], k3[8], k3[5], k3[2]]
+}
+
+pub fn xbrz(src: &[u32], w: usize, h: usize, n: usize, dst: &mut [u32]) {
+    let shapes = &SHAPES[n - 2];
+    let dw = w * n;
+
+    // corners[y * w + x] is the corner to the bottom right of (x, y)
+    let mut corners = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let mut k4 = [0; 16];
+            for (pos, color) in k4.iter_mut().enumerate() {
+                let (dx, dy) = ((pos % 4) as isize - 1, (pos / 4) as isize - 1);
+                *color = pixel(src, w, h, x as isize + dx, y as isize + dy);
+            }
+            corners.push(corner(&k4));
+        }
+    }
+
+    for y in 0..h {
+        for x in 0..w {
+            // corners past the top and left edges are never blended
+            let mut blend = [Blend::None; 4];
+            blend[BOTTOM_RIGHT] = corners[y * w + x].0;
+            if x > 0 {
+                blend[BOTTOM_LEFT] = corners[y * w + x - 1].1;
+            }
+            if y > 0 {
+                blend[TOP_RIGHT] = corners[(y - 1) * w + x].2;
+            }
+            if x > 0 && y > 0 {
+                blend[TOP_LEFT] = corners[(y - 1) * w + x - 1].3;
+            }
+
+            let pos = y * n * dw + x * n;
+            let color = src[y * w + x];
+            for row in 0..n {
+                for col in 0..n {
+                    dst[pos + row * dw + col] = color;
+                }
+            }
+            if blend.iter().all(|&b| b == Blend::None) {
+                continue;
+            }
+
+            let mut k3 = [0; 9];
+            for (i, color) in k3.iter_mut().enumerate() {
+                let (dx, dy) = ((i % 3) as isize - 1, (i / 3) as isize - 1);
+                *color = pixel(src, w, h, x as isize + dx, y as isize + dy);
+            }
+            let mut out = Block { dst: &mut *dst, dw, pos, n, rotation: 0 };
+            for rotation in 0..4 {
+                out.rotation = rotation;
+                blend_pixel(&k3, &blend, shapes, &mut out);
+                k3 = rotate(&k3);
+                blend.rotate_right(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u32 = 0xFFFFFF;
+    const BLACK: u32 = 0x000000;
+
+    fn scale(src: &[u32], w: usize, h: usize, n: usize) -> Vec<u32> {
+        let mut dst = vec![0; w * h * n * n];
+        xbrz(src, w, h, n, &mut dst);
+        dst
+    }
+
+    // the n x n block of source pixel (x, y)
+    fn block(dst: &[u32], w: usize, n: usize, x: usize, y: usize) -> Vec<u32> {
+        let mut out = Vec::new();
+        for row in 0..n {
+            for col in 0..n {
+                out.push(dst[(y * n + row) * w * n + x * n + col]);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn flat_stays_flat() {
+        for n in 2..7 {
+            let src = [0x123456; 16];
+            assert!(scale(&src, 4, 4, n).iter().all(|&c| c == 0x123456));
+        }
+    }
+
+    #[test]
+    fn lone_pixel_gets_round_corners() {
+        // all four corners are blended but each one has another blended
+        // corner next to it, so they're drawn as round corners
+        let mut src = [BLACK; 25];
+        src[12] = WHITE;
+
+        let dst = scale(&src, 5, 5, 2);
+        assert_eq!(block(&dst, 5, 2, 2, 2), vec![0xC9C9C9; 4]);
+        assert_eq!(dst.iter().filter(|&&c| c != BLACK).count(), 4);
+
+        // 45/100 of black in the corners at 3x
+        let dst = scale(&src, 5, 5, 3);
+        let c = 0x8C8C8C;
+        assert_eq!(block(&dst, 5, 3, 2, 2),
+                   vec![c, WHITE, c, WHITE, WHITE, WHITE, c, WHITE, c]);
+    }
+
+    #[test]
+    fn staircase_becomes_a_diagonal() {
+        // white above a 45 degree staircase, black below it
+        let mut src = [BLACK; 36];
+        for y in 0..6 {
+            for x in 0..6 - y {
+                src[y * 6 + x] = WHITE;
+            }
+        }
+        let dst = scale(&src, 6, 6, 2);
+        // the corners to the bottom right of (3, 2) and (3, 1) are clear
+        // edges, the pixels either side get half of the other colour
+        assert_eq!(block(&dst, 6, 2, 3, 2), vec![WHITE, WHITE, WHITE, 0x7F7F7F]);
+        assert_eq!(block(&dst, 6, 2, 4, 2), vec![0x7F7F7F, BLACK, BLACK, BLACK]);
+    }
+
+    #[test]
+    fn distance() {
+        assert_eq!(dist(WHITE, WHITE), 0.0);
+        assert!((dist(WHITE, BLACK) - 255.0).abs() < 0.001);
+        assert!(eq(0x808080, 0x8A8A8A));
+        assert!(!eq(0x808080, 0xA0A0A0));
+    }
+}
@@ -0,0 +1,86 @@
+// The Zapper light gun in port 2. Reads of $4017 give the trigger in bit 4
+// and the light sensor in bit 3, which reads 0 while it sees light. The
+// sensor's photodiode picks up the CRT beam as it passes the spot the gun
+// is aimed at and stays lit for a while after, so games watch $4017 for a
+// few scanlines after drawing a white box over the target.
+use joy::OPEN_BUS;
+use ppu::PPU;
+
+const TRIGGER: u8 = 1 << 4;
+const NO_LIGHT: u8 = 1 << 3;
+
+// pixels around the aim point the sensor sees
+const RADIUS: usize = 2;
+// scanlines the sensor stays lit after the beam goes past, real guns
+// manage somewhere between 10 and 25
+const PERSISTENCE: usize = 20;
+
+pub struct Zapper {
+    // NES pixel under the mouse, None when it's off the picture
+    pub aim: Option<(usize, usize)>,
+    pub trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper {
+            aim: None,
+            trigger: false,
+        }
+    }
+
+    // reading doesn't shift anything so strobing $4016 doesn't matter
+    pub fn read(&self, ppu: &PPU) -> u8 {
+        let mut value = OPEN_BUS;
+        if self.trigger {
+            value |= TRIGGER;
+        }
+        if !self.sees_light(ppu) {
+            value |= NO_LIGHT;
+        }
+        value
+    }
+
+    // Whether a bright pixel near the aim point was drawn within the last
+    // PERSISTENCE scanlines. The screen buffer fills in as the PPU renders,
+    // so anything the beam hasn't reached yet is still last frame's and
+    // doesn't count.
+    fn sees_light(&self, ppu: &PPU) -> bool {
+        let (x, y) = match self.aim {
+            Some(aim) => aim,
+            None => return false,
+        };
+        // pre-render line, nothing of this frame drawn yet
+        if ppu.scanline < 0 {
+            return false;
+        }
+        let scanline = ppu.scanline as usize;
+        let dot = ppu.cycles as usize;
+
+        let rows = y.saturating_sub(RADIUS)..(y + RADIUS + 1).min(240);
+        for row in rows {
+            if scanline < row || scanline - row > PERSISTENCE {
+                continue;
+            }
+            let cols = x.saturating_sub(RADIUS)..(x + RADIUS + 1).min(256);
+            for col in cols {
+                // pixel col goes out on dot col + 1
+                if scanline == row && dot <= col {
+                    break;
+                }
+                if bright(ppu.screen[row][col]) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+// Roughly the colors at least as bright as the darkest gray: the darkest
+// row of colors and the blacks in columns $D-$F don't set the sensor off.
+fn bright(pixel: u16) -> bool {
+    let hue = pixel & 0x0F;
+    let level = (pixel >> 4) & 0x03;
+    hue < 0x0D && (level > 0 || hue == 0)
+}